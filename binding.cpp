@@ -1,12 +1,15 @@
 #include "common.h"
 #include "llama.h"
 #include "common/common.h"
+#include "common/grammar-parser.h"
 
 #include "binding.h"
 
 #include <cassert>
 #include <cinttypes>
+#include <algorithm>
 #include <cmath>
+#include <cstdarg>
 #include <cstdio>
 #include <cstring>
 #include <fstream>
@@ -25,6 +28,50 @@
 #include <signal.h>
 #endif
 
+// Formats a message and hands it to the Rust logger, see llama_binding_log_set.
+static void binding_log(int level, const char *format, ...)
+{
+    va_list args;
+    va_start(args, format);
+    va_list args_copy;
+    va_copy(args_copy, args);
+    const int len = vsnprintf(nullptr, 0, format, args);
+    va_end(args);
+    if (len < 0)
+    {
+        va_end(args_copy);
+        return;
+    }
+    std::vector<char> buf(len + 1);
+    vsnprintf(buf.data(), buf.size(), format, args_copy);
+    va_end(args_copy);
+    logCallback(level, buf.data());
+}
+
+static void llama_log(ggml_log_level level, const char *text, void *user_data)
+{
+    switch (level)
+    {
+    case GGML_LOG_LEVEL_ERROR:
+        logCallback(LLAMA_BINDING_LOG_ERROR, text);
+        break;
+    case GGML_LOG_LEVEL_WARN:
+        logCallback(LLAMA_BINDING_LOG_WARN, text);
+        break;
+    case GGML_LOG_LEVEL_INFO:
+        logCallback(LLAMA_BINDING_LOG_INFO, text);
+        break;
+    default:
+        logCallback(LLAMA_BINDING_LOG_DEBUG, text);
+        break;
+    }
+}
+
+void llama_binding_log_set(void)
+{
+    llama_log_set(llama_log, nullptr);
+}
+
 #if defined(__unix__) || (defined(__APPLE__) && defined(__MACH__)) || defined(_WIN32)
 void sigint_handler(int signo)
 {
@@ -80,7 +127,7 @@ int get_embeddings(void *params_ptr, void *state_pr, float *res_embeddings)
     {
         if (llama_eval(ctx, embd_inp.data(), embd_inp.size(), n_past))
         {
-            fprintf(stderr, "%s : failed to eval\n", __func__);
+            binding_log(LLAMA_BINDING_LOG_ERROR, "%s : failed to eval\n", __func__);
             return 1;
         }
     }
@@ -131,7 +178,7 @@ int eval(void *params_ptr, void *state_pr, char *text)
 
     if (n_prompt_tokens < 1)
     {
-        fprintf(stderr, "%s : failed to tokenize prompt\n", __func__);
+        binding_log(LLAMA_BINDING_LOG_ERROR, "%s : failed to tokenize prompt\n", __func__);
         return 1;
     }
 
@@ -139,7 +186,7 @@ int eval(void *params_ptr, void *state_pr, char *text)
     return llama_eval(ctx, tokens.data(), n_prompt_tokens, n_past);
 }
 
-int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug)
+int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug, int *kv_tokens, int n_kv_tokens, int kv_tokens_cap, int *n_kv_tokens_out, bool custom_sampler, const int *prompt_tokens, int n_prompt_tokens, int end_token, llama_binding_predict_stats *stats)
 {
     gpt_params *params_p = (gpt_params *)params_ptr;
     llama_context *ctx = (llama_context *)state_pr;
@@ -148,17 +195,22 @@ int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug)
 
     const int n_ctx = llama_n_ctx(ctx);
 
+    *stats = {};
+    stats->finish_reason = LLAMA_BINDING_FINISH_LENGTH;
+
     if (params_p->seed <= 0)
     {
         params_p->seed = time(NULL);
     }
 
     std::mt19937 rng(params_p->seed);
+    // llama.cpp's sampling rng otherwise carries on from the previous predict
+    llama_set_rng_seed(ctx, params_p->seed);
 
     // print input
     if (debug)
     {
-        fprintf(stderr, "%s: input: %s\n", __func__, params_p->prompt.c_str());
+        binding_log(LLAMA_BINDING_LOG_DEBUG, "%s: input: %s\n", __func__, params_p->prompt.c_str());
     }
 
     std::string path_session = params_p->path_prompt_cache;
@@ -168,7 +220,7 @@ int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug)
     {
         if (debug)
         {
-            fprintf(stderr, "%s: attempting to load saved session from '%s'\n", __func__, path_session.c_str());
+            binding_log(LLAMA_BINDING_LOG_DEBUG, "%s: attempting to load saved session from '%s'\n", __func__, path_session.c_str());
         }
         // fopen to check for existing session
         FILE *fp = std::fopen(path_session.c_str(), "rb");
@@ -180,27 +232,42 @@ int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug)
             size_t n_token_count_out = 0;
             if (!llama_load_session_file(ctx, path_session.c_str(), session_tokens.data(), session_tokens.capacity(), &n_token_count_out))
             {
-                fprintf(stderr, "%s: error: failed to load session file '%s'\n", __func__, path_session.c_str());
+                binding_log(LLAMA_BINDING_LOG_ERROR, "%s: error: failed to load session file '%s'\n", __func__, path_session.c_str());
                 return 1;
             }
             session_tokens.resize(n_token_count_out);
             llama_set_rng_seed(ctx, params_p->seed);
             if (debug)
             {
-                fprintf(stderr, "%s: loaded a session with prompt size of %d tokens\n", __func__, (int)session_tokens.size());
+                binding_log(LLAMA_BINDING_LOG_DEBUG, "%s: loaded a session with prompt size of %d tokens\n", __func__, (int)session_tokens.size());
             }
         }
         else
         {
             if (debug)
             {
-                fprintf(stderr, "%s: session file does not exist, will create\n", __func__);
+                binding_log(LLAMA_BINDING_LOG_DEBUG, "%s: session file does not exist, will create\n", __func__);
             }
         }
     }
+    else if (n_kv_tokens > 0)
+    {
+        // the context still holds these tokens from a previous call or a restored checkpoint,
+        // so a matching prompt prefix does not need to be evaluated again
+        session_tokens.assign(kv_tokens, kv_tokens + n_kv_tokens);
+        if (debug)
+        {
+            binding_log(LLAMA_BINDING_LOG_DEBUG, "%s: reusing %d tokens already in the context\n", __func__, n_kv_tokens);
+        }
+    }
 
     std::vector<llama_token> embd_inp;
-    if (!params_p->prompt.empty() || session_tokens.empty())
+    if (n_prompt_tokens > 0)
+    {
+        // tokenized by the caller, e.g. to place special tokens around the text
+        embd_inp.assign(prompt_tokens, prompt_tokens + n_prompt_tokens);
+    }
+    else if (!params_p->prompt.empty() || session_tokens.empty())
     {
         // Add a space in front of the first character to match OG llama tokenizer behavior
         params_p->prompt.insert(0, 1, ' ');
@@ -212,6 +279,13 @@ int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug)
         embd_inp = session_tokens;
     }
 
+    stats->prompt_tokens = (int)embd_inp.size();
+    if ((int)embd_inp.size() > n_ctx - 4)
+    {
+        binding_log(LLAMA_BINDING_LOG_ERROR, "%s: error: prompt is too long (%d tokens, max %d)\n", __func__, (int)embd_inp.size(), n_ctx - 4);
+        return 3;
+    }
+
     // debug message about similarity of saved session, if applicable
     size_t n_matching_session_tokens = 0;
     if (session_tokens.size())
@@ -228,21 +302,21 @@ int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug)
         {
             if (params_p->prompt.empty() && n_matching_session_tokens == embd_inp.size())
             {
-                fprintf(stderr, "%s: using full prompt from session file\n", __func__);
+                binding_log(LLAMA_BINDING_LOG_DEBUG, "%s: using full prompt from session file\n", __func__);
             }
             else if (n_matching_session_tokens >= embd_inp.size())
             {
-                fprintf(stderr, "%s: session file has exact match for prompt!\n", __func__);
+                binding_log(LLAMA_BINDING_LOG_DEBUG, "%s: session file has exact match for prompt!\n", __func__);
             }
             else if (n_matching_session_tokens < (embd_inp.size() / 2))
             {
-                fprintf(stderr, "%s: warning: session file has low similarity to prompt (%zu / %zu tokens); will mostly be reevaluated\n",
-                        __func__, n_matching_session_tokens, embd_inp.size());
+                binding_log(LLAMA_BINDING_LOG_WARN, "%s: warning: session file has low similarity to prompt (%zu / %zu tokens); will mostly be reevaluated\n",
+                            __func__, n_matching_session_tokens, embd_inp.size());
             }
             else
             {
-                fprintf(stderr, "%s: session file matches %zu / %zu tokens of prompt\n",
-                        __func__, n_matching_session_tokens, embd_inp.size());
+                binding_log(LLAMA_BINDING_LOG_DEBUG, "%s: session file matches %zu / %zu tokens of prompt\n",
+                            __func__, n_matching_session_tokens, embd_inp.size());
             }
         }
     }
@@ -262,11 +336,27 @@ int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug)
     // determine newline token
     auto llama_token_newline = ::llama_tokenize(ctx, "\n", false);
 
+    // the grammar is validated on the Rust side, failing here means llama.cpp disagrees with it
+    struct llama_grammar *grammar = NULL;
+    if (!params_p->sparams.grammar.empty())
+    {
+        grammar_parser::parse_state parsed_grammar = grammar_parser::parse(params_p->sparams.grammar.c_str());
+        if (parsed_grammar.rules.empty() || parsed_grammar.symbol_ids.find("root") == parsed_grammar.symbol_ids.end())
+        {
+            binding_log(LLAMA_BINDING_LOG_ERROR, "%s: failed to parse grammar\n", __func__);
+            return 2;
+        }
+        std::vector<const llama_grammar_element *> grammar_rules(parsed_grammar.c_rules());
+        grammar = llama_grammar_init(grammar_rules.data(), grammar_rules.size(), parsed_grammar.symbol_ids.at("root"));
+    }
+
     // TODO: replace with ring-buffer
     std::vector<llama_token> last_n_tokens(n_ctx);
     std::fill(last_n_tokens.begin(), last_n_tokens.end(), 0);
 
     bool need_to_save_session = !path_session.empty() && n_matching_session_tokens < embd_inp.size();
+    // session_tokens mirrors the context contents until the context is swapped
+    bool track_kv_tokens = true;
     int n_past = 0;
     int n_remain = params_p->n_predict;
     int n_consumed = 0;
@@ -275,14 +365,15 @@ int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug)
     std::vector<llama_token> embd;
     std::string res = "";
 
-    // do one empty run to warm up the model
+    // do one empty run to warm up the model, unless that would wipe tokens we are about to reuse
+    if (session_tokens.empty())
     {
         llama_token tmp[1] = {
             llama_token_bos(llama_get_model(ctx)),
         };
         llama_eval(ctx, tmp, 1, 0);
-        llama_reset_timings(ctx);
     }
+    llama_reset_timings(ctx);
 
     while (n_remain != 0)
     {
@@ -305,6 +396,7 @@ int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug)
 
                 // stop saving session if we run out of context
                 path_session.clear();
+                track_kv_tokens = false;
 
                 // printf("\n---\n");
                 // printf("resetting: '");
@@ -329,6 +421,7 @@ int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug)
 
                     n_past++;
                     n_session_consumed++;
+                    stats->cached_tokens++;
 
                     if (n_session_consumed >= (int)session_tokens.size())
                     {
@@ -353,13 +446,17 @@ int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug)
                 }
                 if (llama_eval(ctx, &embd[i], n_eval, n_past))
                 {
-                    fprintf(stderr, "%s : failed to eval\n", __func__);
+                    binding_log(LLAMA_BINDING_LOG_ERROR, "%s : failed to eval\n", __func__);
+                    if (grammar != NULL)
+                    {
+                        llama_grammar_free(grammar);
+                    }
                     return 1;
                 }
                 n_past += n_eval;
             }
 
-            if (embd.size() > 0 && !path_session.empty())
+            if (embd.size() > 0 && track_kv_tokens)
             {
                 session_tokens.insert(session_tokens.end(), embd.begin(), embd.end());
                 n_session_consumed = session_tokens.size();
@@ -413,18 +510,43 @@ int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug)
 
                 llama_token_data_array candidates_p = {candidates.data(), candidates.size(), false};
 
-                // Apply penalties
-                float nl_logit = logits[llama_token_nl(llama_get_model(ctx))];
-                auto last_n_repeat = std::min(std::min((int)last_n_tokens.size(), repeat_last_n), n_ctx);
-                llama_sample_repetition_penalties(ctx, &candidates_p,
-                                                last_n_tokens.data() + last_n_tokens.size() - last_n_repeat,
-                                                last_n_repeat, repeat_penalty, alpha_frequency, alpha_presence);
-                if (!penalize_nl)
+                // Apply penalties, a custom sampler chain does its own
+                if (!custom_sampler)
+                {
+                    float nl_logit = logits[llama_token_nl(llama_get_model(ctx))];
+                    auto last_n_repeat = std::min(std::min((int)last_n_tokens.size(), repeat_last_n), n_ctx);
+                    llama_sample_repetition_penalties(ctx, &candidates_p,
+                                                    last_n_tokens.data() + last_n_tokens.size() - last_n_repeat,
+                                                    last_n_repeat, repeat_penalty, alpha_frequency, alpha_presence);
+                    if (!penalize_nl)
+                    {
+                        logits[llama_token_nl(llama_get_model(ctx))] = nl_logit;
+                    }
+                }
+
+                if (grammar != NULL)
                 {
-                    logits[llama_token_nl(llama_get_model(ctx))] = nl_logit;
+                    llama_sample_grammar(ctx, &candidates_p, grammar);
                 }
 
-                if (temp <= 0)
+                // keep the full distribution around, the samplers below sort and truncate candidates
+                std::vector<llama_token_data> probs;
+                if (params_p->sparams.n_probs >= 0)
+                {
+                    probs.assign(candidates_p.data, candidates_p.data + candidates_p.size);
+                }
+
+                if (custom_sampler)
+                {
+                    // the Rust sampler chain picks the token, a negative id means nothing was left to pick
+                    id = samplerCallback(state_pr, candidates_p.data, candidates_p.size, last_n_tokens.data(), (int)last_n_tokens.size());
+                    if (id < 0)
+                    {
+                        stats->finish_reason = LLAMA_BINDING_FINISH_EOS;
+                        break;
+                    }
+                }
+                else if (temp <= 0)
                 {
                     // Greedy sampling
                     id = llama_sample_token_greedy(ctx, &candidates_p);
@@ -457,21 +579,68 @@ int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug)
                 }
                 // printf("`%d`", candidates_p.size);
 
+                if (!probs.empty())
+                {
+                    // log-softmax over the candidates after penalties and grammar
+                    float max_logit = -INFINITY;
+                    for (auto &p : probs)
+                    {
+                        max_logit = std::max(max_logit, p.logit);
+                    }
+                    double sum = 0.0;
+                    for (auto &p : probs)
+                    {
+                        sum += std::exp(p.logit - max_logit);
+                    }
+                    const float log_sum = max_logit + (float)std::log(sum);
+
+                    // probs is still indexed by token id here
+                    const float logprob = probs[id].logit - log_sum;
+
+                    const int n_top = std::min((int)probs.size(), params_p->sparams.n_probs);
+                    std::partial_sort(probs.begin(), probs.begin() + n_top, probs.end(),
+                                      [](const llama_token_data &a, const llama_token_data &b)
+                                      { return a.logit > b.logit; });
+                    std::vector<int> top_tokens(n_top);
+                    std::vector<float> top_logprobs(n_top);
+                    for (int i = 0; i < n_top; i++)
+                    {
+                        top_tokens[i] = probs[i].id;
+                        top_logprobs[i] = probs[i].logit - log_sum;
+                    }
+
+                    logprobsCallback(state_pr, id, logprob, top_tokens.data(), top_logprobs.data(), n_top);
+                }
+
+                if (grammar != NULL)
+                {
+                    llama_grammar_accept_token(ctx, grammar, id);
+                }
+
                 last_n_tokens.erase(last_n_tokens.begin());
                 last_n_tokens.push_back(id);
             }
 
+            // an extra end token, such as the end of an infill, ends generation like EOS
+            if (id == end_token)
+            {
+                stats->finish_reason = LLAMA_BINDING_FINISH_EOS;
+                break;
+            }
+
             // add it to the context
             embd.push_back(id);
 
             // decrement remaining sampling budget
             --n_remain;
+            stats->completion_tokens++;
 
             // call the token callback, no need to check if one is actually registered, that will
             // be handled on the Go side.
             auto token_str = llama_token_to_str(ctx, id);
             if (!tokenCallback(state_pr, (char*)token_str.c_str()))
             {
+                stats->finish_reason = LLAMA_BINDING_FINISH_CANCELLED;
                 break;
             }
         }
@@ -515,6 +684,7 @@ int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug)
 
                 if (last_output.find(antiprompt.c_str(), search_start_pos) != std::string::npos)
                 {
+                    stats->finish_reason = LLAMA_BINDING_FINISH_STOP;
                     goto end;
                 }
             }
@@ -523,6 +693,7 @@ int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug)
         // end of text token
         if (!embd.empty() && embd.back() == llama_token_eos(llama_get_model(ctx)))
         {
+            stats->finish_reason = LLAMA_BINDING_FINISH_EOS;
             break;
         }
     }
@@ -531,7 +702,7 @@ int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug)
     {
         if (debug)
         {
-            fprintf(stderr, "\n%s: saving final output to session file '%s'\n", __func__, path_session.c_str());
+            binding_log(LLAMA_BINDING_LOG_DEBUG, "%s: saving final output to session file '%s'\n", __func__, path_session.c_str());
         }
         llama_save_session_file(ctx, path_session.c_str(), session_tokens.data(), session_tokens.size());
     }
@@ -541,12 +712,30 @@ end:
     signal(SIGINT, SIG_DFL);
 #endif
 
+    if (grammar != NULL)
+    {
+        llama_grammar_free(grammar);
+    }
+
+    {
+        const llama_timings timings = llama_get_timings(ctx);
+        stats->prompt_eval_ms = timings.t_p_eval_ms;
+        stats->gen_ms = timings.t_eval_ms + timings.t_sample_ms;
+    }
+
     if (debug)
     {
         llama_print_timings(ctx);
         llama_reset_timings(ctx);
     }
 
+    *n_kv_tokens_out = 0;
+    if (track_kv_tokens && (int)session_tokens.size() <= kv_tokens_cap)
+    {
+        std::copy(session_tokens.begin(), session_tokens.end(), kv_tokens);
+        *n_kv_tokens_out = (int)session_tokens.size();
+    }
+
     strcpy(result, res.c_str());
     return 0;
 }
@@ -557,6 +746,192 @@ void llama_binding_free_model(void *state_ptr)
     llama_free(ctx);
 }
 
+size_t llama_binding_state_size(void *state_ptr)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_get_state_size(ctx);
+}
+
+size_t llama_binding_copy_state(void *state_ptr, uint8_t *dst)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_copy_state_data(ctx, dst);
+}
+
+size_t llama_binding_set_state(void *state_ptr, uint8_t *src)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_set_state_data(ctx, src);
+}
+
+int llama_binding_tokenize(void *state_ptr, const char *text, int text_len, int *tokens, int n_max_tokens, bool add_bos)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_tokenize(llama_get_model(ctx), text, text_len, tokens, n_max_tokens, add_bos, false);
+}
+
+int llama_binding_token_to_piece(void *state_ptr, int token, char *buf, int length)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_token_to_piece(llama_get_model(ctx), token, buf, length);
+}
+
+int llama_binding_n_vocab(void *state_ptr)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_n_vocab(llama_get_model(ctx));
+}
+
+int llama_binding_token_bos(void *state_ptr)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_token_bos(llama_get_model(ctx));
+}
+
+int llama_binding_token_eos(void *state_ptr)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_token_eos(llama_get_model(ctx));
+}
+
+int llama_binding_token_nl(void *state_ptr)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_token_nl(llama_get_model(ctx));
+}
+
+int llama_binding_apply_lora(void *state_ptr, const char *path, float scale, const char *base_model, int n_threads)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_model_apply_lora_from_file(llama_get_model(ctx), path, scale, base_model[0] != '\0' ? base_model : NULL, n_threads);
+}
+
+void llama_binding_set_n_threads(void *state_ptr, int n_threads)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    llama_set_n_threads(ctx, n_threads, n_threads);
+}
+
+int llama_binding_decode(void *state_ptr, const int *tokens, const int *pos, const int *seq_ids, int n_tokens, int n_logits, float *logits)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    llama_batch batch = llama_batch_init(n_tokens, 0, 1);
+    for (int i = 0; i < n_tokens; i++)
+    {
+        batch.token[i] = tokens[i];
+        batch.pos[i] = pos[i];
+        batch.n_seq_id[i] = 1;
+        batch.seq_id[i][0] = seq_ids[i];
+        // logits are only needed for the last n_logits tokens
+        batch.logits[i] = i >= n_tokens - n_logits;
+    }
+    batch.n_tokens = n_tokens;
+
+    int ret = llama_decode(ctx, batch);
+    if (ret == 0)
+    {
+        const int n_vocab = llama_n_vocab(llama_get_model(ctx));
+        for (int i = 0; i < n_logits; i++)
+        {
+            const float *row = llama_get_logits_ith(ctx, n_tokens - n_logits + i);
+            std::copy(row, row + n_vocab, logits + (size_t)i * n_vocab);
+        }
+    }
+
+    llama_batch_free(batch);
+    return ret;
+}
+
+void llama_binding_kv_seq_rm(void *state_ptr, int seq_id, int p0, int p1)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    llama_kv_cache_seq_rm(ctx, seq_id, p0, p1);
+}
+
+void llama_binding_kv_seq_cp(void *state_ptr, int src, int dst, int p0, int p1)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    llama_kv_cache_seq_cp(ctx, src, dst, p0, p1);
+}
+
+int llama_binding_token_prefix(void *state_ptr)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_token_prefix(llama_get_model(ctx));
+}
+
+int llama_binding_token_suffix(void *state_ptr)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_token_suffix(llama_get_model(ctx));
+}
+
+int llama_binding_token_middle(void *state_ptr)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_token_middle(llama_get_model(ctx));
+}
+
+int llama_binding_token_eot(void *state_ptr)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_token_eot(llama_get_model(ctx));
+}
+
+int llama_binding_model_desc(void *state_ptr, char *buf, size_t buf_size)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_model_desc(llama_get_model(ctx), buf, buf_size);
+}
+
+uint64_t llama_binding_model_n_params(void *state_ptr)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_model_n_params(llama_get_model(ctx));
+}
+
+uint64_t llama_binding_model_size(void *state_ptr)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_model_size(llama_get_model(ctx));
+}
+
+int llama_binding_n_ctx(void *state_ptr)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_n_ctx(ctx);
+}
+
+int llama_binding_n_ctx_train(void *state_ptr)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_n_ctx_train(llama_get_model(ctx));
+}
+
+int llama_binding_n_embd(void *state_ptr)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_n_embd(llama_get_model(ctx));
+}
+
+int llama_binding_meta_count(void *state_ptr)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_model_meta_count(llama_get_model(ctx));
+}
+
+int llama_binding_meta_key(void *state_ptr, int i, char *buf, size_t buf_size)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_model_meta_key_by_index(llama_get_model(ctx), i, buf, buf_size);
+}
+
+int llama_binding_meta_val(void *state_ptr, int i, char *buf, size_t buf_size)
+{
+    llama_context *ctx = (llama_context *)state_ptr;
+    return llama_model_meta_val_str_by_index(llama_get_model(ctx), i, buf, buf_size);
+}
+
 void llama_free_params(void *params_ptr)
 {
     gpt_params *params = (gpt_params *)params_ptr;
@@ -589,14 +964,14 @@ int load_state(void *ctx, char *statefile, char *modes)
         FILE *fp_read = fopen(statefile, modes);
         if (state_size != llama_get_state_size(constState))
         {
-            fprintf(stderr, "\n%s : failed to validate state size\n", __func__);
+            binding_log(LLAMA_BINDING_LOG_ERROR, "%s : failed to validate state size\n", __func__);
             return 1;
         }
 
         const size_t ret = fread(state_mem, 1, state_size, fp_read);
         if (ret != state_size)
         {
-            fprintf(stderr, "\n%s : failed to read state\n", __func__);
+            binding_log(LLAMA_BINDING_LOG_ERROR, "%s : failed to read state\n", __func__);
             return 1;
         }
 
@@ -625,8 +1000,8 @@ void save_state(void *ctx, char *dst, char *modes)
 
 void *llama_allocate_params(const char *prompt, int seed, int threads, int tokens, int top_k,
                             float top_p, float temp, float repeat_penalty, int repeat_last_n, bool ignore_eos, bool memory_f16, int n_batch, int n_keep, const char **antiprompt, int antiprompt_count,
-                            float tfs_z, float typical_p, float frequency_penalty, float presence_penalty, int mirostat, float mirostat_eta, float mirostat_tau, bool penalize_nl, const char *logit_bias, const char *session_file, bool prompt_cache_all, bool mlock, bool mmap,
-                            const char *maingpu, const char *tensorsplit, bool prompt_cache_ro)
+                            float tfs_z, float typical_p, float frequency_penalty, float presence_penalty, int mirostat, float mirostat_eta, float mirostat_tau, bool penalize_nl, const int *logit_bias_tokens, const float *logit_bias_values, int n_logit_bias, const char *session_file, bool prompt_cache_all, bool mlock, bool mmap,
+                            const char *maingpu, const char *tensorsplit, bool prompt_cache_ro, const char *grammar, int n_probs)
 {
     gpt_params *params = new gpt_params;
     params->seed = seed;
@@ -674,11 +1049,8 @@ void *llama_allocate_params(const char *prompt, int seed, int threads, int token
     params->prompt_cache_all = prompt_cache_all;
     params->path_prompt_cache = session_file;
 
-    /* if (ignore_eos) // TODO: Cannot be set before context is allocated (llama_token_eos requires context access)
-    {
-        params->logit_bias[llama_token_eos()] = -INFINITY;
-	}
-    */
+    // ignore_eos arrives as a -INFINITY entry of the logit bias, the EOS token
+    // isn't known before the context is allocated
     if (antiprompt_count > 0)
     {
         params->antiprompt = create_vector(antiprompt, antiprompt_count);
@@ -690,21 +1062,27 @@ void *llama_allocate_params(const char *prompt, int seed, int threads, int token
     params->sparams.mirostat_eta = mirostat_eta;
     params->sparams.mirostat_tau = mirostat_tau;
     params->sparams.penalize_nl = penalize_nl;
-    std::stringstream ss(logit_bias);
-    llama_token key;
-    char sign;
-    std::string value_str;
-    if (ss >> key && ss >> sign && std::getline(ss, value_str) && (sign == '+' || sign == '-'))
+    for (int i = 0; i < n_logit_bias; i++)
     {
-        params->sparams.logit_bias[key] = std::stof(value_str) * ((sign == '-') ? -1.0f : 1.0f);
+        params->sparams.logit_bias[logit_bias_tokens[i]] = logit_bias_values[i];
     }
     params->sparams.penalty_freq = frequency_penalty;
+    params->sparams.grammar = grammar;
+    // unlike llama.cpp, 0 still reports the sampled token's logprob and a negative value disables it
+    params->sparams.n_probs = n_probs;
     params->prompt = prompt;
 
     return params;
 }
 
-void *load_model(const char *fname, int n_ctx, int n_seed, bool memory_f16, bool mlock, bool embeddings, bool mmap, bool low_vram, bool vocab_only, int n_gpu_layers, int n_batch, const char *maingpu, const char *tensorsplit, bool numa)
+// Forwards llama.cpp's loading progress, from 0 to 1, to Rust.
+static bool load_progress(float progress, void *user_data)
+{
+    loadProgressCallback(user_data, progress);
+    return true;
+}
+
+void *load_model(const char *fname, int n_ctx, int n_seed, bool memory_f16, bool mlock, bool embeddings, bool mmap, bool low_vram, bool vocab_only, int n_gpu_layers, int n_batch, const char *maingpu, const char *tensorsplit, bool numa, void *progress_user_data)
 {
     // load the model
     auto lparams = llama_context_default_params();
@@ -719,6 +1097,11 @@ void *load_model(const char *fname, int n_ctx, int n_seed, bool memory_f16, bool
     mparams.use_mmap = mmap;
     // mparams.low_vram = low_vram; LOW_VRAM not a thing anymore in the API? verify
     mparams.vocab_only = vocab_only;
+    if (progress_user_data != nullptr)
+    {
+        mparams.progress_callback = load_progress;
+        mparams.progress_callback_user_data = progress_user_data;
+    }
 
     if (maingpu[0] != '\0')
     {
@@ -762,7 +1145,7 @@ void *load_model(const char *fname, int n_ctx, int n_seed, bool memory_f16, bool
     }
     catch (std::runtime_error &e)
     {
-        fprintf(stderr, "failed %s", e.what());
+        binding_log(LLAMA_BINDING_LOG_ERROR, "failed %s\n", e.what());
         return res;
     }
 
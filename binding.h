@@ -6,16 +6,48 @@ extern "C"
 #endif
 
 #include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#define LLAMA_BINDING_FINISH_LENGTH 0
+#define LLAMA_BINDING_FINISH_EOS 1
+#define LLAMA_BINDING_FINISH_STOP 2
+#define LLAMA_BINDING_FINISH_CANCELLED 3
+
+#define LLAMA_BINDING_LOG_ERROR 0
+#define LLAMA_BINDING_LOG_WARN 1
+#define LLAMA_BINDING_LOG_INFO 2
+#define LLAMA_BINDING_LOG_DEBUG 3
+
+    typedef struct llama_binding_predict_stats
+    {
+        int finish_reason;
+        int prompt_tokens;
+        int completion_tokens;
+        int cached_tokens;
+        double prompt_eval_ms;
+        double gen_ms;
+    } llama_binding_predict_stats;
 
     extern unsigned char tokenCallback(void *, char *);
 
+    extern void logprobsCallback(void *, int, float, int *, float *, int);
+
+    extern int samplerCallback(void *, void *, size_t, int *, int);
+
+    extern void loadProgressCallback(void *, float);
+
+    extern void logCallback(int, const char *);
+
+    void llama_binding_log_set(void);
+
     int load_state(void *ctx, char *statefile, char *modes);
 
     int eval(void *params_ptr, void *ctx, char *text);
 
     void save_state(void *ctx, char *dst, char *modes);
 
-    void *load_model(const char *fname, int n_ctx, int n_seed, bool memory_f16, bool mlock, bool embeddings, bool mmap, bool low_vram, bool vocab_only, int n_gpu, int n_batch, const char *maingpu, const char *tensorsplit, bool numa);
+    void *load_model(const char *fname, int n_ctx, int n_seed, bool memory_f16, bool mlock, bool embeddings, bool mmap, bool low_vram, bool vocab_only, int n_gpu, int n_batch, const char *maingpu, const char *tensorsplit, bool numa, void *progress_user_data);
 
     int get_embeddings(void *params_ptr, void *state_pr, float *res_embeddings);
 
@@ -25,13 +57,67 @@ extern "C"
                                 int top_k, float top_p, float temp, float repeat_penalty,
                                 int repeat_last_n, bool ignore_eos, bool memory_f16,
                                 int n_batch, int n_keep, const char **antiprompt, int antiprompt_count,
-                                float tfs_z, float typical_p, float frequency_penalty, float presence_penalty, int mirostat, float mirostat_eta, float mirostat_tau, bool penalize_nl, const char *logit_bias, const char *session_file, bool prompt_cache_all, bool mlock, bool mmap, const char *maingpu, const char *tensorsplit, bool prompt_cache_ro);
+                                float tfs_z, float typical_p, float frequency_penalty, float presence_penalty, int mirostat, float mirostat_eta, float mirostat_tau, bool penalize_nl, const int *logit_bias_tokens, const float *logit_bias_values, int n_logit_bias, const char *session_file, bool prompt_cache_all, bool mlock, bool mmap, const char *maingpu, const char *tensorsplit, bool prompt_cache_ro, const char *grammar, int n_probs);
 
     void llama_free_params(void *params_ptr);
 
     void llama_binding_free_model(void *state);
 
-    int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug);
+    int llama_predict(void *params_ptr, void *state_pr, char *result, bool debug, int *kv_tokens, int n_kv_tokens, int kv_tokens_cap, int *n_kv_tokens_out, bool custom_sampler, const int *prompt_tokens, int n_prompt_tokens, int end_token, llama_binding_predict_stats *stats);
+
+    size_t llama_binding_state_size(void *state);
+
+    size_t llama_binding_copy_state(void *state, uint8_t *dst);
+
+    size_t llama_binding_set_state(void *state, uint8_t *src);
+
+    int llama_binding_tokenize(void *state, const char *text, int text_len, int *tokens, int n_max_tokens, bool add_bos);
+
+    int llama_binding_token_to_piece(void *state, int token, char *buf, int length);
+
+    int llama_binding_n_vocab(void *state);
+
+    int llama_binding_token_bos(void *state);
+
+    int llama_binding_token_eos(void *state);
+
+    int llama_binding_token_nl(void *state);
+
+    int llama_binding_apply_lora(void *state, const char *path, float scale, const char *base_model, int n_threads);
+
+    void llama_binding_set_n_threads(void *state, int n_threads);
+
+    int llama_binding_decode(void *state, const int *tokens, const int *pos, const int *seq_ids, int n_tokens, int n_logits, float *logits);
+
+    void llama_binding_kv_seq_rm(void *state, int seq_id, int p0, int p1);
+
+    void llama_binding_kv_seq_cp(void *state, int src, int dst, int p0, int p1);
+
+    int llama_binding_token_prefix(void *state);
+
+    int llama_binding_token_suffix(void *state);
+
+    int llama_binding_token_middle(void *state);
+
+    int llama_binding_token_eot(void *state);
+
+    int llama_binding_model_desc(void *state, char *buf, size_t buf_size);
+
+    uint64_t llama_binding_model_n_params(void *state);
+
+    uint64_t llama_binding_model_size(void *state);
+
+    int llama_binding_n_ctx(void *state);
+
+    int llama_binding_n_ctx_train(void *state);
+
+    int llama_binding_n_embd(void *state);
+
+    int llama_binding_meta_count(void *state);
+
+    int llama_binding_meta_key(void *state, int i, char *buf, size_t buf_size);
+
+    int llama_binding_meta_val(void *state, int i, char *buf, size_t buf_size);
 
 #ifdef __cplusplus
 }
@@ -143,6 +143,7 @@ fn compile_llama(cxx: &mut Build, cxx_flags: &str, out_path: &PathBuf, ggml_type
 
     cxx.shared_flag(true)
         .file("./llama.cpp/common/common.cpp")
+        .file("./llama.cpp/common/grammar-parser.cpp")
         .file("./llama.cpp/llama.cpp")
         .file("./binding.cpp")
         .cpp(true)
@@ -1,7 +1,7 @@
-use echoma::{client::Client, Result};
+use clap::Parser;
+use echoma::{cli::Cli, Result};
 
 #[tokio::main]
 pub async fn main() -> Result<()> {
-    let client = Client::new().await?;
-    client.start().await
+    Cli::parse().run().await
 }
@@ -0,0 +1,268 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    llama::{options::PredictOptions, LLama},
+    session::{IOPair, Session},
+    Result,
+};
+
+const CHECKPOINT_MAGIC: &[u8; 8] = b"ECHOCKPT";
+pub const CHECKPOINT_VERSION: u32 = 1;
+
+// magic + version + checksum + body length
+const HEADER_LEN: usize = 8 + 4 + 32 + 8;
+
+#[derive(Serialize, Deserialize)]
+struct CheckpointMeta {
+    model_hash: String,
+    seed: i32,
+    options: PredictOptions,
+    turns: Vec<IOPair>,
+    kv_tokens: Vec<i32>,
+}
+
+/// A conversation snapshot: the session transcript, the context state and the
+/// options that produced it, tied to the model file it was taken from.
+///
+/// On disk a checkpoint is a fixed header (magic, format version, SHA-256 of the
+/// body, body length) followed by a body holding the JSON metadata and the raw
+/// context state.
+pub struct Checkpoint {
+    meta: CheckpointMeta,
+    kv_state: Vec<u8>,
+}
+
+impl Checkpoint {
+    pub(crate) fn capture(llama: &LLama, session: &Session, opts: &PredictOptions) -> Result<Self> {
+        Ok(Self {
+            meta: CheckpointMeta {
+                model_hash: llama.model_hash()?,
+                seed: opts.seed,
+                options: opts.copy_settings()?,
+                turns: session.pairs().to_vec(),
+                kv_tokens: llama.kv_tokens(),
+            },
+            kv_state: llama.state_bytes(),
+        })
+    }
+
+    /// Puts the transcript and context state back and returns the options the
+    /// checkpoint was taken with. The next predict resumes from the restored
+    /// context without evaluating the saved tokens again.
+    pub(crate) fn restore(self, llama: &LLama, session: &mut Session) -> Result<PredictOptions> {
+        self.check_model(&llama.model_hash()?, llama.model_path())?;
+
+        llama.set_state_bytes(&self.kv_state, &self.meta.kv_tokens)?;
+        session.replace_pairs(self.meta.turns);
+
+        let mut options = self.meta.options;
+        options.seed = self.meta.seed;
+        Ok(options)
+    }
+
+    // Fails unless the checkpoint was taken with the model of this hash.
+    fn check_model(&self, model_hash: &str, model_path: &str) -> Result<()> {
+        if model_hash != self.meta.model_hash {
+            return Err(format!(
+                "checkpoint was taken with model {} but {} ({}) is loaded",
+                self.meta.model_hash, model_path, model_hash
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    pub fn model_hash(&self) -> &str {
+        &self.meta.model_hash
+    }
+
+    pub fn seed(&self) -> i32 {
+        self.meta.seed
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let meta = serde_json::to_vec(&self.meta)?;
+
+        let mut body = Vec::with_capacity(8 + meta.len() + self.kv_state.len());
+        body.extend_from_slice(&(meta.len() as u64).to_le_bytes());
+        body.extend_from_slice(&meta);
+        body.extend_from_slice(&self.kv_state);
+
+        let mut file = File::create(path)?;
+        file.write_all(CHECKPOINT_MAGIC)?;
+        file.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+        file.write_all(&Sha256::digest(&body))?;
+        file.write_all(&(body.len() as u64).to_le_bytes())?;
+        file.write_all(&body)?;
+        file.flush()?;
+
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+
+        if data.len() < HEADER_LEN || &data[..8] != CHECKPOINT_MAGIC {
+            return Err(format!("{} is not a checkpoint file", path).into());
+        }
+
+        let version = u32::from_le_bytes(data[8..12].try_into()?);
+        if version != CHECKPOINT_VERSION {
+            return Err(format!(
+                "unsupported checkpoint version {} (expected {})",
+                version, CHECKPOINT_VERSION
+            )
+            .into());
+        }
+
+        let checksum = &data[12..44];
+        let body_len = u64::from_le_bytes(data[44..52].try_into()?) as usize;
+        let body = &data[HEADER_LEN..];
+        if body.len() != body_len || Sha256::digest(body).as_slice() != checksum {
+            return Err(format!("checkpoint {} is corrupt or truncated", path).into());
+        }
+
+        if body.len() < 8 {
+            return Err(format!("checkpoint {} is corrupt or truncated", path).into());
+        }
+        let meta_len = u64::from_le_bytes(body[..8].try_into()?) as usize;
+        if body.len() - 8 < meta_len {
+            return Err(format!("checkpoint {} is corrupt or truncated", path).into());
+        }
+
+        let meta: CheckpointMeta = serde_json::from_slice(&body[8..8 + meta_len])?;
+        let kv_state = body[8 + meta_len..].to_vec();
+
+        Ok(Self { meta, kv_state })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn checkpoint() -> Checkpoint {
+        let mut session = Session::new();
+        session.append("hi", "hello");
+        let options = PredictOptions {
+            seed: 42,
+            ..Default::default()
+        };
+        Checkpoint {
+            meta: CheckpointMeta {
+                model_hash: "abc123".to_string(),
+                seed: 42,
+                options,
+                turns: session.pairs().to_vec(),
+                kv_tokens: vec![1, 2, 3],
+            },
+            kv_state: (0..64).collect(),
+        }
+    }
+
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("echoma-{}-{}.ckpt", name, std::process::id())))
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let file = TempFile::new("round-trip");
+        checkpoint().save(file.path()).unwrap();
+
+        let loaded = Checkpoint::load(file.path()).unwrap();
+        assert_eq!(loaded.model_hash(), "abc123");
+        assert_eq!(loaded.seed(), 42);
+        assert_eq!(loaded.meta.kv_tokens, vec![1, 2, 3]);
+        assert_eq!(loaded.meta.turns.len(), 1);
+        assert_eq!(loaded.meta.options.seed, 42);
+        assert_eq!(loaded.kv_state, (0..64).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn header_layout() {
+        let file = TempFile::new("header");
+        checkpoint().save(file.path()).unwrap();
+
+        let data = std::fs::read(file.path()).unwrap();
+        assert_eq!(&data[..8], CHECKPOINT_MAGIC);
+        assert_eq!(&data[8..12], &CHECKPOINT_VERSION.to_le_bytes());
+        let body = &data[HEADER_LEN..];
+        assert_eq!(&data[12..44], Sha256::digest(body).as_slice());
+        assert_eq!(&data[44..52], &(body.len() as u64).to_le_bytes());
+    }
+
+    #[test]
+    fn load_rejects_a_changed_body() {
+        let file = TempFile::new("checksum");
+        checkpoint().save(file.path()).unwrap();
+        let mut data = std::fs::read(file.path()).unwrap();
+        *data.last_mut().unwrap() ^= 0xff;
+        std::fs::write(file.path(), &data).unwrap();
+
+        let err = Checkpoint::load(file.path()).err().unwrap();
+        assert!(err.to_string().contains("corrupt"), "{}", err);
+    }
+
+    #[test]
+    fn load_rejects_a_truncated_file() {
+        let file = TempFile::new("truncated");
+        checkpoint().save(file.path()).unwrap();
+        let data = std::fs::read(file.path()).unwrap();
+        std::fs::write(file.path(), &data[..data.len() - 10]).unwrap();
+
+        assert!(Checkpoint::load(file.path()).is_err());
+        std::fs::write(file.path(), &data[..20]).unwrap();
+        assert!(Checkpoint::load(file.path()).is_err());
+    }
+
+    #[test]
+    fn load_rejects_other_files() {
+        let file = TempFile::new("magic");
+        std::fs::write(file.path(), [0u8; HEADER_LEN + 16]).unwrap();
+        let err = Checkpoint::load(file.path()).err().unwrap();
+        assert!(err.to_string().contains("not a checkpoint"), "{}", err);
+    }
+
+    #[test]
+    fn load_rejects_other_versions() {
+        let file = TempFile::new("version");
+        checkpoint().save(file.path()).unwrap();
+        let mut data = std::fs::read(file.path()).unwrap();
+        data[8..12].copy_from_slice(&(CHECKPOINT_VERSION + 1).to_le_bytes());
+        std::fs::write(file.path(), &data).unwrap();
+
+        let err = Checkpoint::load(file.path()).err().unwrap();
+        assert!(err.to_string().contains("version"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_a_different_model() {
+        let checkpoint = checkpoint();
+        assert!(checkpoint.check_model("abc123", "a.gguf").is_ok());
+        let err = checkpoint.check_model("def456", "b.gguf").err().unwrap();
+        assert!(err.to_string().contains("b.gguf"), "{}", err);
+    }
+}
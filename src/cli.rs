@@ -0,0 +1,237 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use clap::{Parser, Subcommand};
+
+use crate::{
+    client::Client,
+    config::{
+        config_idle_unload, config_model_or_default, set_global_config, Config, DEFAULT_CONFIG_FILE,
+    },
+    llama::{
+        gguf::GgufFile,
+        metadata::ModelMetadata,
+        options::{ModelOptions, PredictOptions},
+        spawn_idle_unloader, LLama,
+    },
+    utils::progress_bar,
+    Result,
+};
+
+#[derive(Parser)]
+#[command(name = "echoma", version, about = "Chat with a local llama.cpp model")]
+pub struct Cli {
+    /// Config file, defaults to echoma.toml when it exists
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Start the interactive chat (default)
+    Chat,
+    /// Print the metadata of a model file, or list a directory of models
+    Info {
+        model: String,
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+        /// Load the model through llama.cpp instead of only reading its header
+        #[arg(long)]
+        load: bool,
+    },
+    /// Print the token ids and pieces of a text
+    Tokenize {
+        text: String,
+        /// Model file, defaults to the configured model
+        #[arg(long)]
+        model: Option<String>,
+        /// Don't prepend the BOS token
+        #[arg(long)]
+        no_bos: bool,
+    },
+    /// Complete the code between a prefix and a suffix with a fill-in-the-middle model
+    Infill {
+        prefix: String,
+        #[arg(default_value = "")]
+        suffix: String,
+        /// Model file, defaults to the configured model
+        #[arg(long)]
+        model: Option<String>,
+        /// Maximum number of tokens to generate
+        #[arg(long, default_value_t = 256)]
+        tokens: i32,
+    },
+}
+
+impl Cli {
+    pub async fn run(self) -> Result<()> {
+        let config_path = self.config.or_else(|| {
+            let default = PathBuf::from(DEFAULT_CONFIG_FILE);
+            default.exists().then_some(default)
+        });
+        if let Some(path) = config_path {
+            set_global_config(Config::load(path)?);
+        }
+
+        match self.command.unwrap_or(Command::Chat) {
+            Command::Chat => {
+                if let Some(timeout) = config_idle_unload() {
+                    spawn_idle_unloader(timeout);
+                }
+                Client::new().await?.start().await
+            }
+            Command::Info { model, json, load } => {
+                if Path::new(&model).is_dir() {
+                    list_models(&model, json)
+                } else {
+                    info(model, json, load)
+                }
+            }
+            Command::Tokenize {
+                text,
+                model,
+                no_bos,
+            } => tokenize(model, &text, !no_bos),
+            Command::Infill {
+                prefix,
+                suffix,
+                model,
+                tokens,
+            } => infill(model, &prefix, &suffix, tokens),
+        }
+    }
+}
+
+// Loads a model with a progress bar on stderr.
+fn load_model(model: String, opts: &ModelOptions) -> Result<LLama> {
+    let mut shown = false;
+    let llama = LLama::with_progress(model, opts, |progress| {
+        eprint!("\r{}", progress_bar(progress));
+        shown = true;
+    });
+    if shown {
+        eprintln!();
+    }
+    Ok(llama?)
+}
+
+fn info(model: String, json: bool, load: bool) -> Result<()> {
+    let metadata = if load {
+        let mut model_options = ModelOptions::default();
+        model_options.set_context(512);
+        load_model(model, &model_options)?.metadata()?
+    } else {
+        ModelMetadata::from_gguf(&GgufFile::open(model)?)
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&metadata)?);
+    } else {
+        print!("{}", metadata);
+    }
+
+    Ok(())
+}
+
+fn list_models(dir: &str, json: bool) -> Result<()> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "gguf"))
+        .collect();
+    paths.sort();
+
+    let mut entries = vec![];
+    for path in paths {
+        let name = path.display().to_string();
+        match GgufFile::open(&path) {
+            Ok(gguf) => {
+                let metadata = ModelMetadata::from_gguf(&gguf);
+                if !json {
+                    println!(
+                        "{}  {}  {} params  {}  n_ctx_train {}  {} bytes",
+                        name,
+                        metadata.architecture,
+                        metadata.n_params,
+                        metadata.quantization,
+                        metadata.n_ctx_train,
+                        metadata.file_size
+                    );
+                }
+                entries.push(serde_json::json!({ "path": name, "metadata": metadata }));
+            }
+            Err(e) => {
+                if !json {
+                    println!("{}  invalid: {}", name, e);
+                }
+                entries.push(serde_json::json!({ "path": name, "error": e.to_string() }));
+            }
+        }
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    }
+
+    Ok(())
+}
+
+fn tokenize(model: Option<String>, text: &str, add_bos: bool) -> Result<()> {
+    // Only the vocabulary is needed, keep the context small.
+    let mut model_options = ModelOptions::default();
+    model_options.set_context(512);
+    let llama = load_model(
+        model.unwrap_or_else(config_model_or_default),
+        &model_options,
+    )?;
+
+    let tokens = llama.tokenize(text, add_bos)?;
+    for token in &tokens {
+        let mut marks = vec![];
+        if *token == llama.token_bos() {
+            marks.push("bos");
+        }
+        if *token == llama.token_eos() {
+            marks.push("eos");
+        }
+        if *token == llama.token_nl() {
+            marks.push("nl");
+        }
+        println!(
+            "{:>8}  {:?}  {}",
+            token,
+            llama.token_to_piece(*token)?,
+            marks.join(",")
+        );
+    }
+    println!("{} tokens", tokens.len());
+
+    Ok(())
+}
+
+fn infill(model: Option<String>, prefix: &str, suffix: &str, tokens: i32) -> Result<()> {
+    let llama = load_model(
+        model.unwrap_or_else(config_model_or_default),
+        &ModelOptions::default(),
+    )?;
+
+    let mut predict_options = PredictOptions::default();
+    predict_options.set_tokens(tokens);
+    predict_options.set_token_callback(Some(Box::new(|token| {
+        print!("{}", token);
+        std::io::stdout().flush().is_ok()
+    })));
+
+    let result = llama.infill(prefix, suffix, predict_options)?;
+    println!();
+    eprintln!(
+        "[{} prompt tokens, {} generated, finish: {}]",
+        result.prompt_tokens, result.completion_tokens, result.finish_reason
+    );
+
+    Ok(())
+}
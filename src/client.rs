@@ -1,12 +1,15 @@
+use std::io::Write;
+
 use tokio::{
     io::{self, AsyncBufReadExt, BufReader},
     sync::mpsc,
 };
 
 use crate::{
-    cmd::{CmdRes, Executor},
+    cmd::{Cmd, CmdRes, Executor},
     session::CURRENT_SESSION,
-    Result, USER_CHATTING_NAME_SHORT,
+    utils::progress_bar,
+    Result, LOGGER,
 };
 
 pub struct Client {}
@@ -28,28 +31,79 @@ impl Client {
 
                 let (tx, mut rx) = mpsc::channel(5);
                 let executor = Executor::new(user_input.as_str(), tx)?;
+                let record_turn = !matches!(
+                    executor.cmd,
+                    Cmd::Save(_)
+                        | Cmd::Load(_)
+                        | Cmd::Lora(_)
+                        | Cmd::Model(_)
+                        | Cmd::Use(_)
+                        | Cmd::Models
+                );
 
                 tokio::spawn(async move {
-                    let _ = executor.apply().await;
+                    if let Err(err) = executor.apply().await {
+                        slog::error!(LOGGER, "failed to send the command result: {}", err);
+                    }
                 });
 
                 println!("Echo:");
 
                 let mut output: Vec<String> = Default::default();
+                let mut progress_shown = false;
                 while let Some(cmd_res) = rx.recv().await {
+                    if progress_shown && !matches!(cmd_res, CmdRes::Progress(_)) {
+                        println!();
+                        progress_shown = false;
+                    }
                     match cmd_res {
                         CmdRes::Content(content) => {
                             output.push(content.clone());
                         }
-                        CmdRes::Over => {
-                            let output_str = output.join("").replace(USER_CHATTING_NAME_SHORT, "");
+                        CmdRes::Status(status) => {
+                            println!("[{}]", status);
+                        }
+                        CmdRes::Progress(progress) => {
+                            print!("\r{}", progress_bar(progress));
+                            std::io::stdout().flush()?;
+                            progress_shown = true;
+                        }
+                        CmdRes::Over(result) => {
+                            let output_str = output.join("");
                             let output_str = output_str.trim();
-                            CURRENT_SESSION
-                                .lock()
-                                .await
-                                .append(user_input.as_str(), output_str);
+                            if record_turn {
+                                CURRENT_SESSION
+                                    .lock()
+                                    .await
+                                    .append(user_input.as_str(), output_str);
+                            }
                             output.clear();
                             println!("{}", output_str);
+                            // stats go to the log, so the conversation stays readable
+                            if let Some(result) = result {
+                                slog::info!(
+                                    LOGGER,
+                                    "{} prompt tokens ({} cached), {} generated, {:.1} tokens/s, finish: {}",
+                                    result.prompt_tokens,
+                                    result.cached_tokens,
+                                    result.completion_tokens,
+                                    result.tokens_per_sec,
+                                    result.finish_reason
+                                );
+                                if let Some(speculative) = result.speculative {
+                                    slog::info!(
+                                        LOGGER,
+                                        "{} of {} draft tokens accepted ({:.0}%)",
+                                        speculative.accepted,
+                                        speculative.drafted,
+                                        speculative.acceptance_rate() * 100.0
+                                    );
+                                }
+                            }
+                            break;
+                        }
+                        CmdRes::Error(message) => {
+                            println!("Error: {}", message);
                             break;
                         }
                         CmdRes::Exit => {
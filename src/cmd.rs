@@ -1,28 +1,85 @@
+use std::time::Duration;
+
 use tokio::sync::mpsc;
 
 use crate::{
-    llama::{options::PredictOptions, LOCAL_LLAMA},
-    session::CURRENT_SESSION,
-    Result, USER_CHATTING_NAME, USER_CHATTING_NAME_SHORT,
+    checkpoint::Checkpoint,
+    llama::{
+        default_model_options,
+        manager::{ModelGuard, ModelState},
+        options::{LoraAdapter, PredictOptions},
+        result::PredictResult,
+        MODEL_MANAGER, MODEL_REGISTRY,
+    },
+    session::{Session, CURRENT_SESSION},
+    Result, LOGGER, USER_CHATTING_NAME,
 };
 
 pub enum CmdRes {
     Content(String),
-    Over,
+    /// Progress shown to the user, not part of the reply.
+    Status(String),
+    /// Fraction of the model loaded, while a request waits for it.
+    Progress(f32),
+    /// The command is done, with the prediction stats if it ran one.
+    Over(Option<PredictResult>),
+    /// The command failed, ends it like `Over`.
+    Error(String),
     Exit,
 }
 
 pub enum Cmd {
     Greeting,
     Exit,
+    Save(String),
+    Load(String),
+    /// Uses a LoRA adapter in this session, `None` to use none.
+    Lora(Option<LoraAdapter>),
+    /// Switches to the model at the path once it's loaded.
+    Model(String),
+    /// Uses a model of the `[models]` config in this session, `None` for the
+    /// default model.
+    Use(Option<String>),
+    /// Lists the models of the `[models]` config.
+    Models,
     Message(String),
 }
 
 impl From<&str> for Cmd {
     fn from(value: &str) -> Self {
+        if let Some(path) = value.strip_prefix("/save ") {
+            return Cmd::Save(path.trim().to_string());
+        }
+        if let Some(path) = value.strip_prefix("/load ") {
+            return Cmd::Load(path.trim().to_string());
+        }
+        if let Some(path) = value.strip_prefix("/model ") {
+            return Cmd::Model(path.trim().to_string());
+        }
+        if let Some(alias) = value.strip_prefix("/use ") {
+            let alias = alias.trim();
+            return Cmd::Use((alias != "default").then(|| alias.to_string()));
+        }
+        if let Some(arg) = value.strip_prefix("/lora ") {
+            let arg = arg.trim();
+            if arg == "off" {
+                return Cmd::Lora(None);
+            }
+            // "/lora <path> [scale]"
+            let adapter = match arg.rsplit_once(' ') {
+                Some((path, scale)) => match scale.parse() {
+                    Ok(scale) => LoraAdapter::new(path.trim(), scale),
+                    Err(_) => LoraAdapter::new(arg, 1.0),
+                },
+                None => LoraAdapter::new(arg, 1.0),
+            };
+            return Cmd::Lora(Some(adapter));
+        }
+
         match value.to_lowercase().as_str() {
             "hi echo" => Cmd::Greeting,
             "exit" => Cmd::Exit,
+            "/models" => Cmd::Models,
             _ => Cmd::Message(value.to_string()),
         }
     }
@@ -41,37 +98,277 @@ impl Executor {
         })
     }
 
+    /// Runs the command. Failures are sent as `CmdRes::Error`, an error is only
+    /// returned when the result can't be sent at all.
     pub async fn apply(&self) -> Result<()> {
+        if let Err(err) = self.run().await {
+            slog::error!(LOGGER, "command failed: {}", err);
+            let message = match &self.cmd {
+                Cmd::Save(path) => format!("Couldn't save the checkpoint to {}: {}", path, err),
+                Cmd::Load(path) => format!("Couldn't load the checkpoint from {}: {}", path, err),
+                Cmd::Lora(_) => format!("Couldn't change the LoRA adapters: {}", err),
+                Cmd::Model(path) => format!("Couldn't switch to the model {}: {}", path, err),
+                Cmd::Use(_) => format!("Couldn't switch models: {}", err),
+                Cmd::Message(_) => format!("Couldn't generate a reply: {}", err),
+                Cmd::Greeting | Cmd::Exit | Cmd::Models => err.to_string(),
+            };
+            self.result_sender.send(CmdRes::Error(message)).await?;
+        }
+        Ok(())
+    }
+
+    // The model a session with the given registry alias predicts with, telling
+    // the user when it has to be loaded first.
+    async fn session_model(&self, alias: Option<&str>) -> Result<ModelGuard> {
+        let (path, state) = match alias {
+            Some(alias) => {
+                let slot = MODEL_REGISTRY.slot(alias)?;
+                (slot.path().to_string(), slot.state())
+            }
+            None => {
+                let slot = MODEL_MANAGER.current();
+                (slot.path().to_string(), slot.state())
+            }
+        };
+        if state != ModelState::Loaded {
+            self.result_sender
+                .send(CmdRes::Status(format!("loading model {}", path)))
+                .await?;
+        }
+
+        let load = async {
+            match alias {
+                Some(alias) => MODEL_REGISTRY.get(alias).await,
+                None => MODEL_MANAGER.get().await,
+            }
+        };
+        tokio::pin!(load);
+        let mut ticks = tokio::time::interval(Duration::from_millis(200));
+        loop {
+            tokio::select! {
+                llama = &mut load => return Ok(llama?),
+                _ = ticks.tick() => {
+                    let progress = match alias {
+                        Some(alias) => MODEL_REGISTRY.slot(alias)?.loading_progress(),
+                        None => MODEL_MANAGER.current().loading_progress(),
+                    };
+                    if let Some(progress) = progress {
+                        self.result_sender.send(CmdRes::Progress(progress)).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn run(&self) -> Result<()> {
         match &self.cmd {
             Cmd::Greeting => {
                 CURRENT_SESSION.lock().await.clear();
                 self.result_sender
                     .send(CmdRes::Content("Hello, what can I do for you?".to_string()))
                     .await?;
-                self.result_sender.send(CmdRes::Over).await
+                self.result_sender.send(CmdRes::Over(None)).await
             }
             Cmd::Exit => self.result_sender.send(CmdRes::Exit).await,
+            Cmd::Save(path) => {
+                let session = CURRENT_SESSION.lock().await;
+                let llama = self.session_model(session.model()).await?;
+                let checkpoint =
+                    Checkpoint::capture(&llama, &session, &session_options(&session)?)?;
+                checkpoint.save(path)?;
+                self.result_sender
+                    .send(CmdRes::Content(format!("Checkpoint saved to {}", path)))
+                    .await?;
+                self.result_sender.send(CmdRes::Over(None)).await
+            }
+            Cmd::Load(path) => {
+                let mut session = CURRENT_SESSION.lock().await;
+                let llama = self.session_model(session.model()).await?;
+                // later messages generate with the seed and options the checkpoint was taken with
+                let options = Checkpoint::load(path)?.restore(&llama, &mut session)?;
+                session.set_options(options);
+                let turns = session.pairs().len();
+                drop(session);
+                self.result_sender
+                    .send(CmdRes::Content(format!(
+                        "Checkpoint restored from {} ({} turns)",
+                        path, turns
+                    )))
+                    .await?;
+                self.result_sender.send(CmdRes::Over(None)).await
+            }
+            Cmd::Lora(adapter) => {
+                let mut session = CURRENT_SESSION.lock().await;
+                let llama = self.session_model(session.model()).await?;
+                // a session that hasn't picked adapters yet starts from the attached ones
+                let mut adapters = match session.lora_adapters() {
+                    Some(adapters) => adapters.clone(),
+                    None => llama.lora_adapters(),
+                };
+                match adapter {
+                    Some(adapter) => {
+                        adapters.retain(|a| a.path != adapter.path);
+                        adapters.push(adapter.clone());
+                    }
+                    None => adapters.clear(),
+                }
+                // apply right away, so that a bad adapter fails here and not on the next message
+                llama.set_lora_adapters(&adapters)?;
+                session.set_lora_adapters(adapters.clone());
+                drop(session);
+
+                let names: Vec<String> = adapters
+                    .iter()
+                    .map(|a| format!("{} ({})", a.path, a.scale))
+                    .collect();
+                let content = if names.is_empty() {
+                    "No LoRA adapters in use".to_string()
+                } else {
+                    format!("LoRA adapters in use: {}", names.join(", "))
+                };
+                self.result_sender.send(CmdRes::Content(content)).await?;
+                self.result_sender.send(CmdRes::Over(None)).await
+            }
+            Cmd::Model(path) => {
+                self.result_sender
+                    .send(CmdRes::Status(format!("loading model {}", path)))
+                    .await?;
+                let llama = MODEL_MANAGER
+                    .swap(path.clone(), default_model_options())
+                    .await?;
+                // the session moves to the new model, its adapters don't carry over
+                let mut session = CURRENT_SESSION.lock().await;
+                session.set_model(None);
+                session.clear_lora_adapters();
+                drop(session);
+                self.result_sender
+                    .send(CmdRes::Content(format!(
+                        "Switched to {}",
+                        llama.model_path()
+                    )))
+                    .await?;
+                self.result_sender.send(CmdRes::Over(None)).await
+            }
+            Cmd::Use(alias) => {
+                let mut session = CURRENT_SESSION.lock().await;
+                // load it now, so that a missing model fails here and not on the next message
+                let llama = self.session_model(alias.as_deref()).await?;
+                session.set_model(alias.clone());
+                session.clear_lora_adapters();
+                drop(session);
+
+                self.result_sender
+                    .send(CmdRes::Content(format!(
+                        "Using {} ({})",
+                        alias.as_deref().unwrap_or("default"),
+                        llama.model_path()
+                    )))
+                    .await?;
+                self.result_sender.send(CmdRes::Over(None)).await
+            }
+            Cmd::Models => {
+                let default = MODEL_MANAGER.current();
+                let mut lines = vec![format!(
+                    "default  {}  {}",
+                    default.path(),
+                    state_name(default.state())
+                )];
+                for model in MODEL_REGISTRY.list() {
+                    lines.push(format!(
+                        "{}  {}  {}",
+                        model.alias,
+                        model.path,
+                        state_name(model.state)
+                    ));
+                }
+                let content = lines.join("\n");
+                self.result_sender.send(CmdRes::Content(content)).await?;
+                self.result_sender.send(CmdRes::Over(None)).await
+            }
             Cmd::Message(message) => {
-                let prompt = CURRENT_SESSION.lock().await.gen_prompt(&message);
+                let mut session = CURRENT_SESSION.lock().await;
+                let prompt = session.gen_prompt(&message);
+                let lora_adapters = session.lora_adapters().cloned();
+                let options = session_options(&session)?;
+                let llama = self.session_model(session.model()).await?;
+                drop(session);
 
                 let sender = self.result_sender.clone();
                 let predict_options = PredictOptions {
                     token_callback: Some(Box::new(move |token| {
-                        if !token.contains(USER_CHATTING_NAME_SHORT) {
-                            let sender = sender.clone();
-                            tokio::spawn(async move { sender.send(CmdRes::Content(token)).await });
-                            true
-                        } else {
-                            false
-                        }
+                        let sender = sender.clone();
+                        tokio::spawn(async move { sender.send(CmdRes::Content(token)).await });
+                        true
                     })),
-                    stop_prompts: vec![USER_CHATTING_NAME.to_string()],
-                    ..Default::default()
+                    lora_adapters,
+                    ..options
                 };
-                LOCAL_LLAMA.get().await.predict(prompt, predict_options)?;
-                self.result_sender.send(CmdRes::Over).await
+                let result = llama.predict(prompt, predict_options)?;
+                self.result_sender.send(CmdRes::Over(Some(result))).await
             }
         }?;
         Ok(())
     }
 }
+
+fn state_name(state: ModelState) -> &'static str {
+    match state {
+        ModelState::Unloaded => "not loaded",
+        ModelState::Loading => "loading",
+        ModelState::Loaded => "loaded",
+    }
+}
+
+// The options of the session's checkpoint if it was restored from one.
+fn session_options(session: &Session) -> Result<PredictOptions> {
+    match session.options() {
+        Some(options) => Ok(options.copy_settings()?),
+        None => Ok(chat_options()),
+    }
+}
+
+fn chat_options() -> PredictOptions {
+    PredictOptions {
+        stop_prompts: vec![USER_CHATTING_NAME.to_string()],
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_model_commands() {
+        assert!(
+            matches!(Cmd::from("/model  models/phi.gguf "), Cmd::Model(path) if path == "models/phi.gguf")
+        );
+        assert!(matches!(Cmd::from("/use coder"), Cmd::Use(Some(alias)) if alias == "coder"));
+        assert!(matches!(Cmd::from("/use default"), Cmd::Use(None)));
+        assert!(matches!(Cmd::from("/models"), Cmd::Models));
+        // without an argument it's a message
+        assert!(matches!(Cmd::from("/model"), Cmd::Message(_)));
+    }
+
+    #[test]
+    fn parses_lora_commands() {
+        assert!(matches!(Cmd::from("/lora off"), Cmd::Lora(None)));
+        assert!(matches!(
+            Cmd::from("/lora my adapter.bin 0.5"),
+            Cmd::Lora(Some(adapter)) if adapter == LoraAdapter::new("my adapter.bin", 0.5)
+        ));
+        assert!(matches!(
+            Cmd::from("/lora my adapter.bin"),
+            Cmd::Lora(Some(adapter)) if adapter == LoraAdapter::new("my adapter.bin", 1.0)
+        ));
+    }
+
+    #[test]
+    fn parses_other_input() {
+        assert!(matches!(Cmd::from("Hi Echo"), Cmd::Greeting));
+        assert!(matches!(Cmd::from("exit"), Cmd::Exit));
+        assert!(matches!(Cmd::from("/save a.ckpt"), Cmd::Save(path) if path == "a.ckpt"));
+        assert!(matches!(Cmd::from("/load a.ckpt"), Cmd::Load(path) if path == "a.ckpt"));
+        assert!(matches!(Cmd::from("hello there"), Cmd::Message(text) if text == "hello there"));
+    }
+}
@@ -1,7 +1,12 @@
+use std::{collections::HashMap, path::Path, time::Duration};
+
 use serde::Deserialize;
 
+use crate::{llama::options::ModelOptions, Result};
+
 pub const DEFAULT_WEB_PORT: &str = "8633";
 pub const DEFAULT_MODEL: &str = "phi-2.Q4_0.gguf";
+pub const DEFAULT_CONFIG_FILE: &str = "echoma.toml";
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
@@ -10,6 +15,31 @@ pub struct Config {
     log_level: Option<String>,
     log_file: Option<String>,
     model: Option<String>,
+    draft_model: Option<String>,
+    /// Whether `model` is memory-mapped, the default. LoRA adapters can only be
+    /// attached with `/lora` when it's set to false.
+    m_map: Option<bool>,
+    /// Memory the models of `models` may take together before the least
+    /// recently used ones are unloaded.
+    models_memory_mb: Option<u64>,
+    /// Models no request has used for this long are unloaded until needed again.
+    idle_unload_secs: Option<u64>,
+    #[serde(default)]
+    models: HashMap<String, ModelConfig>,
+}
+
+/// A `[models.<alias>]` entry.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModelConfig {
+    pub path: String,
+    #[serde(flatten)]
+    pub options: ModelOptions,
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
 }
 
 // Config
@@ -92,3 +122,53 @@ pub fn config_model_or_default() -> String {
 
     DEFAULT_MODEL.to_owned()
 }
+
+pub fn config_draft_model() -> Option<String> {
+    unsafe {
+        if let Some(c) = &GLOBAL_CONFIG {
+            return c.draft_model.clone();
+        }
+    }
+
+    None
+}
+
+pub fn config_m_map() -> Option<bool> {
+    unsafe {
+        if let Some(c) = &GLOBAL_CONFIG {
+            return c.m_map;
+        }
+    }
+
+    None
+}
+
+pub fn config_models() -> HashMap<String, ModelConfig> {
+    unsafe {
+        if let Some(c) = &GLOBAL_CONFIG {
+            return c.models.clone();
+        }
+    }
+
+    HashMap::new()
+}
+
+pub fn config_models_memory_limit() -> Option<u64> {
+    unsafe {
+        if let Some(c) = &GLOBAL_CONFIG {
+            return c.models_memory_mb.map(|mb| mb * 1024 * 1024);
+        }
+    }
+
+    None
+}
+
+pub fn config_idle_unload() -> Option<Duration> {
+    unsafe {
+        if let Some(c) = &GLOBAL_CONFIG {
+            return c.idle_unload_secs.map(Duration::from_secs);
+        }
+    }
+
+    None
+}
@@ -4,6 +4,8 @@ use lazy_static::lazy_static;
 use slog::Drain;
 use std::fs::OpenOptions;
 
+pub mod checkpoint;
+pub mod cli;
 pub mod client;
 pub mod cmd;
 pub mod config;
@@ -38,4 +40,3 @@ pub type Error = Box<dyn std::error::Error + Send + Sync>;
 pub type Result<T> = anyhow::Result<T, Error>;
 
 pub const USER_CHATTING_NAME: &str = "Userc33dc3a";
-pub const USER_CHATTING_NAME_SHORT: &str = "Userc33dc3";
@@ -0,0 +1,223 @@
+use serde::{Deserialize, Serialize};
+
+/// One hypothesis of a beam search.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Beam {
+    pub text: String,
+    pub tokens: Vec<i32>,
+    /// Sum of the log-probabilities of the tokens.
+    pub logprob: f32,
+    /// `logprob` divided by the number of tokens raised to the length penalty,
+    /// beams are ranked by it.
+    pub score: f32,
+    /// Whether the beam ended with end of text.
+    pub finished: bool,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Hypothesis {
+    pub(crate) tokens: Vec<i32>,
+    pub(crate) logprob: f32,
+    pub(crate) finished: bool,
+    /// Sequence in the KV cache holding the tokens, or the parent's sequence
+    /// right after `step` until the caller moves it to a new one.
+    pub(crate) seq: i32,
+}
+
+/// The bookkeeping of a beam search, the caller evaluates the live beams and
+/// keeps their KV sequences in sync.
+pub(crate) struct BeamSearch {
+    width: usize,
+    length_penalty: f32,
+    eos: i32,
+    pub(crate) beams: Vec<Hypothesis>,
+}
+
+impl BeamSearch {
+    pub(crate) fn new(width: usize, length_penalty: f32, eos: i32, seq: i32) -> Self {
+        Self {
+            width,
+            length_penalty,
+            eos,
+            beams: vec![Hypothesis {
+                tokens: vec![],
+                logprob: 0.0,
+                finished: false,
+                seq,
+            }],
+        }
+    }
+
+    pub(crate) fn score(&self, beam: &Hypothesis) -> f32 {
+        beam.logprob / (beam.tokens.len().max(1) as f32).powf(self.length_penalty)
+    }
+
+    pub(crate) fn live(&self) -> impl Iterator<Item = &Hypothesis> {
+        self.beams.iter().filter(|beam| !beam.finished)
+    }
+
+    pub(crate) fn live_mut(&mut self) -> impl Iterator<Item = &mut Hypothesis> {
+        self.beams.iter_mut().filter(|beam| !beam.finished)
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.live().next().is_none()
+    }
+
+    /// Extends every live beam by its `width` most likely tokens and keeps the
+    /// `width` best hypotheses, finished ones included. `logits` holds one row
+    /// of `n_vocab` logits per live beam, in order.
+    pub(crate) fn step(&mut self, logits: &[f32], n_vocab: usize) {
+        let mut candidates: Vec<Hypothesis> = self
+            .beams
+            .iter()
+            .filter(|beam| beam.finished)
+            .cloned()
+            .collect();
+
+        for (beam, row) in self.live().zip(logits.chunks(n_vocab)) {
+            let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let log_sum = max + row.iter().map(|l| (l - max).exp()).sum::<f32>().ln();
+
+            let mut ids: Vec<usize> = (0..row.len()).collect();
+            let n_top = self.width.min(ids.len());
+            ids.select_nth_unstable_by(n_top - 1, |&a, &b| row[b].total_cmp(&row[a]));
+            for &id in &ids[..n_top] {
+                if row[id] == f32::NEG_INFINITY {
+                    continue;
+                }
+                let mut tokens = beam.tokens.clone();
+                tokens.push(id as i32);
+                candidates.push(Hypothesis {
+                    tokens,
+                    logprob: beam.logprob + row[id] - log_sum,
+                    finished: id as i32 == self.eos,
+                    seq: beam.seq,
+                });
+            }
+        }
+
+        candidates.sort_by(|a, b| self.score(b).total_cmp(&self.score(a)));
+        candidates.truncate(self.width);
+        self.beams = candidates;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EOS: i32 = 3;
+
+    // A row of logits whose softmax is `probs`.
+    fn row(probs: &[f32]) -> Vec<f32> {
+        probs.iter().map(|p| p.ln()).collect()
+    }
+
+    fn hypothesis(tokens: &[i32], logprob: f32, finished: bool) -> Hypothesis {
+        Hypothesis {
+            tokens: tokens.to_vec(),
+            logprob,
+            finished,
+            seq: 0,
+        }
+    }
+
+    fn tokens(search: &BeamSearch) -> Vec<Vec<i32>> {
+        search
+            .beams
+            .iter()
+            .map(|beam| beam.tokens.clone())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_most_likely_extensions() {
+        let mut search = BeamSearch::new(2, 1.0, EOS, 0);
+        search.step(&row(&[0.1, 0.6, 0.3, 0.0]), 4);
+        assert_eq!(tokens(&search), [vec![1], vec![2]]);
+        assert!((search.beams[0].logprob - 0.6f32.ln()).abs() < 1e-5);
+
+        // every live beam is extended, the best hypotheses overall are kept
+        let rows = [row(&[0.45, 0.35, 0.2, 0.0]), row(&[0.0, 0.0, 0.01, 0.99])];
+        search.step(&rows.concat(), 4);
+        assert_eq!(tokens(&search), [vec![2, 3], vec![1, 0]]);
+        assert!(search.beams[0].finished);
+        assert!(!search.beams[1].finished);
+    }
+
+    #[test]
+    fn finished_beams_are_kept_but_not_extended() {
+        let mut search = BeamSearch::new(2, 1.0, EOS, 0);
+        search.beams = vec![
+            hypothesis(&[EOS], -0.1, true),
+            hypothesis(&[1], -0.2, false),
+        ];
+        // one row, for the only live beam
+        search.step(&row(&[0.5, 0.5, 0.0, 0.0]), 4);
+        assert_eq!(tokens(&search), [vec![EOS], vec![1, 0]]);
+
+        search.step(&row(&[0.0, 0.0, 0.0, 1.0]), 4);
+        assert_eq!(tokens(&search), [vec![EOS], vec![1, 0, EOS]]);
+        assert!(search.is_done());
+    }
+
+    #[test]
+    fn length_penalty_favours_longer_beams() {
+        let beams = vec![
+            hypothesis(&[EOS], -1.0, true),
+            hypothesis(&[1, 1, 1, EOS], -2.0, true),
+        ];
+
+        let mut search = BeamSearch::new(1, 0.0, EOS, 0);
+        search.beams = beams.clone();
+        search.step(&[], 4);
+        assert_eq!(tokens(&search), [vec![EOS]]);
+
+        let mut search = BeamSearch::new(1, 1.0, EOS, 0);
+        search.beams = beams;
+        search.step(&[], 4);
+        assert_eq!(tokens(&search), [vec![1, 1, 1, EOS]]);
+        assert_eq!(search.score(&search.beams[0]), -0.5);
+    }
+
+    #[test]
+    fn banned_tokens_are_never_picked() {
+        let mut search = BeamSearch::new(3, 1.0, EOS, 0);
+        let mut logits = row(&[0.5, 0.5, 0.0, 0.0]);
+        logits[1] = f32::NEG_INFINITY;
+        search.step(&logits, 4);
+        assert!(search.beams.iter().all(|beam| beam.tokens != [1]));
+        assert_eq!(search.beams[0].tokens, [0]);
+    }
+
+    #[test]
+    fn options_beam_search_would_ignore_are_rejected() {
+        use crate::llama::{error::LlamaError, options::PredictOptions};
+
+        let beams = || PredictOptions {
+            beam_width: 2,
+            ..Default::default()
+        };
+        assert!(beams().validate().is_ok());
+        for opts in [
+            PredictOptions {
+                logprobs: Some(1),
+                ..beams()
+            },
+            PredictOptions {
+                logprobs_callback: Some(Box::new(|_| {})),
+                ..beams()
+            },
+            PredictOptions {
+                stop_prompts: vec!["\n".to_string()],
+                ..beams()
+            },
+        ] {
+            assert!(matches!(
+                opts.validate(),
+                Err(LlamaError::InvalidOptions(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use thiserror::Error;
+
+use super::{
+    gguf::GgufError, grammar::GrammarError, json_schema::SchemaError, memory::MemoryEstimate,
+};
+
+#[derive(Debug, Error)]
+pub enum LlamaError {
+    #[error("failed to load model {path}")]
+    ModelLoad { path: String },
+    #[error("prompt of {tokens} tokens does not fit the context of {context_size} tokens")]
+    ContextOverflow { tokens: usize, context_size: usize },
+    #[error("invalid prompt: {0}")]
+    InvalidPrompt(String),
+    #[error("invalid options: {0}")]
+    InvalidOptions(String),
+    #[error("failed to tokenize: {0}")]
+    Tokenize(String),
+    #[error("llama.cpp evaluation failed with code {code}")]
+    Eval { code: i32 },
+    #[error("state I/O failed: {0}")]
+    StateIo(String),
+    #[error("no model named {0} is configured")]
+    UnknownModel(String),
+    #[error("model {path} needs about {needed} bytes, over the limit of {limit} bytes for loaded models")]
+    MemoryLimit {
+        path: String,
+        needed: u64,
+        limit: u64,
+    },
+    #[error(
+        "model {path} needs about {needed} for a context of {context_size} tokens, but only {} MiB of memory is available",
+        .available / (1024 * 1024)
+    )]
+    InsufficientMemory {
+        path: String,
+        needed: MemoryEstimate,
+        context_size: i32,
+        available: u64,
+    },
+    #[error("model loaded without embeddings")]
+    EmbeddingsDisabled,
+    #[error("failed to apply LoRA adapter {path}")]
+    Lora { path: String },
+    #[error("model has no fill-in-the-middle tokens")]
+    InfillUnsupported,
+    #[error("generation was cancelled before it completed")]
+    Cancelled,
+    #[error("a sampler stage panicked")]
+    SamplerPanicked,
+    #[error(transparent)]
+    Gguf(#[from] GgufError),
+    #[error(transparent)]
+    Grammar(#[from] GrammarError),
+    #[error(transparent)]
+    Schema(#[from] SchemaError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
@@ -0,0 +1,434 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use thiserror::Error;
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+const GGUF_DEFAULT_ALIGNMENT: u64 = 32;
+// Counts come from the file, so they only size the first allocation up to this.
+const MAX_PREALLOCATED: usize = 4096;
+// Arrays of arrays are read recursively, this bounds the recursion.
+const MAX_ARRAY_DEPTH: usize = 8;
+
+#[derive(Debug, Error)]
+pub enum GgufError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("not a GGUF file")]
+    BadMagic,
+    #[error("unsupported GGUF version {0}")]
+    UnsupportedVersion(u32),
+    #[error("file is truncated while reading {0}")]
+    Truncated(&'static str),
+    #[error("invalid value type {0}")]
+    InvalidValueType(u32),
+    #[error("invalid UTF-8 in {0}")]
+    InvalidUtf8(&'static str),
+    #[error("arrays nested deeper than {0} levels")]
+    NestedTooDeep(usize),
+    #[error("invalid alignment {0}")]
+    InvalidAlignment(u64),
+    #[error("tensor {0} has {1} dimensions")]
+    InvalidDimensions(String, u32),
+    #[error("tensor {0} lies outside the file")]
+    TensorOutOfBounds(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+impl GgufValue {
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            GgufValue::U8(v) => Some(v as u64),
+            GgufValue::U16(v) => Some(v as u64),
+            GgufValue::U32(v) => Some(v as u64),
+            GgufValue::U64(v) => Some(v),
+            GgufValue::I8(v) => u64::try_from(v).ok(),
+            GgufValue::I16(v) => u64::try_from(v).ok(),
+            GgufValue::I32(v) => u64::try_from(v).ok(),
+            GgufValue::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            GgufValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            GgufValue::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for GgufValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GgufValue::U8(v) => write!(f, "{}", v),
+            GgufValue::I8(v) => write!(f, "{}", v),
+            GgufValue::U16(v) => write!(f, "{}", v),
+            GgufValue::I16(v) => write!(f, "{}", v),
+            GgufValue::U32(v) => write!(f, "{}", v),
+            GgufValue::I32(v) => write!(f, "{}", v),
+            GgufValue::F32(v) => write!(f, "{}", v),
+            GgufValue::Bool(v) => write!(f, "{}", v),
+            GgufValue::String(v) => write!(f, "{}", v),
+            GgufValue::U64(v) => write!(f, "{}", v),
+            GgufValue::I64(v) => write!(f, "{}", v),
+            GgufValue::F64(v) => write!(f, "{}", v),
+            GgufValue::Array(values) => {
+                write!(f, "[")?;
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match v {
+                        GgufValue::String(s) => write!(f, "{:?}", s)?,
+                        _ => write!(f, "{}", v)?,
+                    }
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GgufTensorInfo {
+    pub name: String,
+    pub dims: Vec<u64>,
+    pub ggml_type: u32,
+    /// Offset relative to the start of the tensor data section.
+    pub offset: u64,
+}
+
+impl GgufTensorInfo {
+    pub fn n_elements(&self) -> u64 {
+        self.dims.iter().fold(1u64, |n, &d| n.saturating_mul(d))
+    }
+
+    /// Size of the tensor data, `None` for ggml types this reader doesn't know.
+    pub fn n_bytes(&self) -> Option<u64> {
+        let (block_size, type_size) = ggml_type_size(self.ggml_type)?;
+        Some(self.n_elements() / block_size * type_size)
+    }
+}
+
+/// Header, metadata and tensor directory of a GGUF file, read without loading
+/// any tensor data.
+#[derive(Debug, Clone)]
+pub struct GgufFile {
+    pub version: u32,
+    pub metadata: BTreeMap<String, GgufValue>,
+    pub tensors: Vec<GgufTensorInfo>,
+    pub alignment: u64,
+    pub data_offset: u64,
+    pub file_size: u64,
+}
+
+impl GgufFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, GgufError> {
+        let file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+        let mut reader = GgufReader {
+            inner: BufReader::new(file),
+            pos: 0,
+            len: file_size,
+            version: 0,
+        };
+
+        if &reader.bytes::<4>("magic")? != GGUF_MAGIC {
+            return Err(GgufError::BadMagic);
+        }
+        let version = reader.u32("version")?;
+        if !(1..=3).contains(&version) {
+            return Err(GgufError::UnsupportedVersion(version));
+        }
+        reader.version = version;
+
+        let n_tensors = reader.count("tensor count")?;
+        let n_kv = reader.count("metadata count")?;
+
+        let mut metadata = BTreeMap::new();
+        for _ in 0..n_kv {
+            let key = reader.string("metadata key")?;
+            let value_type = reader.u32("metadata value type")?;
+            let value = reader.value(value_type, 0)?;
+            metadata.insert(key, value);
+        }
+
+        let mut tensors = Vec::new();
+        for _ in 0..n_tensors {
+            let name = reader.string("tensor name")?;
+            let n_dims = reader.u32("tensor dimensions")?;
+            if n_dims == 0 || n_dims > 4 {
+                return Err(GgufError::InvalidDimensions(name, n_dims));
+            }
+            let mut dims = Vec::with_capacity(n_dims as usize);
+            for _ in 0..n_dims {
+                dims.push(reader.count("tensor shape")?);
+            }
+            let ggml_type = reader.u32("tensor type")?;
+            let offset = reader.u64("tensor offset")?;
+            tensors.push(GgufTensorInfo {
+                name,
+                dims,
+                ggml_type,
+                offset,
+            });
+        }
+
+        let alignment = metadata
+            .get("general.alignment")
+            .and_then(GgufValue::as_u64)
+            .unwrap_or(GGUF_DEFAULT_ALIGNMENT);
+        if alignment == 0 || !alignment.is_power_of_two() {
+            return Err(GgufError::InvalidAlignment(alignment));
+        }
+        let data_offset = reader.pos.div_ceil(alignment) * alignment;
+
+        for tensor in &tensors {
+            let n_bytes = tensor.n_bytes().unwrap_or(0);
+            let end = data_offset
+                .checked_add(tensor.offset)
+                .and_then(|start| start.checked_add(n_bytes));
+            if end.is_none_or(|end| end > file_size) {
+                return Err(GgufError::TensorOutOfBounds(tensor.name.clone()));
+            }
+        }
+
+        Ok(Self {
+            version,
+            metadata,
+            tensors,
+            alignment,
+            data_offset,
+            file_size,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.metadata.get(key)
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(GgufValue::as_str)
+    }
+
+    pub fn get_u64(&self, key: &str) -> Option<u64> {
+        self.get(key).and_then(GgufValue::as_u64)
+    }
+
+    pub fn architecture(&self) -> Option<&str> {
+        self.get_str("general.architecture")
+    }
+
+    /// Reads an architecture specific key such as `context_length` (`llama.context_length`).
+    pub fn get_arch_u64(&self, key: &str) -> Option<u64> {
+        self.get_u64(&format!("{}.{}", self.architecture()?, key))
+    }
+
+    pub fn n_params(&self) -> u64 {
+        self.tensors.iter().map(GgufTensorInfo::n_elements).sum()
+    }
+
+    pub fn n_vocab(&self) -> Option<u64> {
+        self.get("tokenizer.ggml.tokens")
+            .and_then(GgufValue::as_array)
+            .map(|tokens| tokens.len() as u64)
+            .or_else(|| self.get_arch_u64("vocab_size"))
+    }
+}
+
+// (elements per block, bytes per block) of the ggml tensor types.
+fn ggml_type_size(ggml_type: u32) -> Option<(u64, u64)> {
+    match ggml_type {
+        0 => Some((1, 4)),      // F32
+        1 => Some((1, 2)),      // F16
+        2 => Some((32, 18)),    // Q4_0
+        3 => Some((32, 20)),    // Q4_1
+        6 => Some((32, 22)),    // Q5_0
+        7 => Some((32, 24)),    // Q5_1
+        8 => Some((32, 34)),    // Q8_0
+        9 => Some((32, 40)),    // Q8_1
+        10 => Some((256, 84)),  // Q2_K
+        11 => Some((256, 110)), // Q3_K
+        12 => Some((256, 144)), // Q4_K
+        13 => Some((256, 176)), // Q5_K
+        14 => Some((256, 210)), // Q6_K
+        15 => Some((256, 292)), // Q8_K
+        _ => None,
+    }
+}
+
+struct GgufReader {
+    inner: BufReader<File>,
+    pos: u64,
+    len: u64,
+    version: u32,
+}
+
+impl GgufReader {
+    fn ensure(&self, n: u64, what: &'static str) -> Result<(), GgufError> {
+        if self.len - self.pos < n {
+            return Err(GgufError::Truncated(what));
+        }
+        Ok(())
+    }
+
+    fn bytes<const N: usize>(&mut self, what: &'static str) -> Result<[u8; N], GgufError> {
+        self.ensure(N as u64, what)?;
+        let mut buf = [0u8; N];
+        self.inner.read_exact(&mut buf)?;
+        self.pos += N as u64;
+        Ok(buf)
+    }
+
+    fn u32(&mut self, what: &'static str) -> Result<u32, GgufError> {
+        Ok(u32::from_le_bytes(self.bytes(what)?))
+    }
+
+    fn u64(&mut self, what: &'static str) -> Result<u64, GgufError> {
+        Ok(u64::from_le_bytes(self.bytes(what)?))
+    }
+
+    // Counts and lengths are 32 bit in version 1 and 64 bit afterwards.
+    fn count(&mut self, what: &'static str) -> Result<u64, GgufError> {
+        if self.version == 1 {
+            Ok(self.u32(what)? as u64)
+        } else {
+            self.u64(what)
+        }
+    }
+
+    fn string(&mut self, what: &'static str) -> Result<String, GgufError> {
+        let len = self.count(what)?;
+        self.ensure(len, what)?;
+        let mut buf = vec![0u8; len as usize];
+        self.inner.read_exact(&mut buf)?;
+        self.pos += len;
+        String::from_utf8(buf).map_err(|_| GgufError::InvalidUtf8(what))
+    }
+
+    fn value(&mut self, value_type: u32, depth: usize) -> Result<GgufValue, GgufError> {
+        const WHAT: &str = "metadata value";
+        Ok(match value_type {
+            0 => GgufValue::U8(u8::from_le_bytes(self.bytes(WHAT)?)),
+            1 => GgufValue::I8(i8::from_le_bytes(self.bytes(WHAT)?)),
+            2 => GgufValue::U16(u16::from_le_bytes(self.bytes(WHAT)?)),
+            3 => GgufValue::I16(i16::from_le_bytes(self.bytes(WHAT)?)),
+            4 => GgufValue::U32(u32::from_le_bytes(self.bytes(WHAT)?)),
+            5 => GgufValue::I32(i32::from_le_bytes(self.bytes(WHAT)?)),
+            6 => GgufValue::F32(f32::from_le_bytes(self.bytes(WHAT)?)),
+            7 => GgufValue::Bool(u8::from_le_bytes(self.bytes(WHAT)?) != 0),
+            8 => GgufValue::String(self.string(WHAT)?),
+            9 => {
+                let elem_type = self.u32("array type")?;
+                if elem_type > 12 {
+                    return Err(GgufError::InvalidValueType(elem_type));
+                }
+                let n = self.count("array length")?;
+                // every element takes at least one byte, a larger count can't fit in the file
+                self.ensure(n, "array")?;
+                if elem_type == 9 && depth + 1 >= MAX_ARRAY_DEPTH {
+                    return Err(GgufError::NestedTooDeep(MAX_ARRAY_DEPTH));
+                }
+                let mut values = Vec::with_capacity((n as usize).min(MAX_PREALLOCATED));
+                for _ in 0..n {
+                    values.push(self.value(elem_type, depth + 1)?);
+                }
+                GgufValue::Array(values)
+            }
+            10 => GgufValue::U64(u64::from_le_bytes(self.bytes(WHAT)?)),
+            11 => GgufValue::I64(i64::from_le_bytes(self.bytes(WHAT)?)),
+            12 => GgufValue::F64(f64::from_le_bytes(self.bytes(WHAT)?)),
+            _ => return Err(GgufError::InvalidValueType(value_type)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> Result<GgufFile, GgufError> {
+        GgufFile::open(format!(
+            "{}/tests/fixtures/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+    }
+
+    #[test]
+    fn reads_a_valid_file() {
+        let gguf = fixture("valid.gguf").unwrap();
+        assert_eq!(gguf.version, 3);
+        assert_eq!(gguf.architecture(), Some("llama"));
+        assert_eq!(gguf.get_str("general.name"), Some("tiny"));
+        assert_eq!(gguf.get_arch_u64("context_length"), Some(2048));
+        assert_eq!(gguf.n_vocab(), Some(3));
+        assert_eq!(gguf.tensors.len(), 1);
+        assert_eq!(gguf.tensors[0].name, "token_embd.weight");
+        assert_eq!(gguf.tensors[0].n_bytes(), Some(48));
+        assert_eq!(gguf.n_params(), 12);
+        assert_eq!(gguf.data_offset % 32, 0);
+        assert_eq!(gguf.data_offset + 48, gguf.file_size);
+    }
+
+    #[test]
+    fn rejects_a_truncated_file() {
+        assert!(matches!(
+            fixture("truncated.gguf"),
+            Err(GgufError::Truncated(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_a_bad_magic() {
+        assert!(matches!(
+            fixture("bad-magic.gguf"),
+            Err(GgufError::BadMagic)
+        ));
+    }
+
+    #[test]
+    fn rejects_a_count_larger_than_the_file() {
+        assert!(matches!(
+            fixture("oversized-count.gguf"),
+            Err(GgufError::Truncated("array"))
+        ));
+    }
+
+    #[test]
+    fn rejects_deeply_nested_arrays() {
+        assert!(matches!(
+            fixture("nested-arrays.gguf"),
+            Err(GgufError::NestedTooDeep(MAX_ARRAY_DEPTH))
+        ));
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::HashSet;
+
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum GrammarError {
+    #[error("grammar syntax error at line {line}, column {column}: {message}")]
+    Syntax {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    #[error("grammar references undefined rule `{0}`")]
+    UndefinedRule(String),
+    #[error("grammar has no `root` rule")]
+    MissingRoot,
+    #[error("grammar was rejected by llama.cpp")]
+    Rejected,
+}
+
+/// Checks a GBNF grammar the way llama.cpp's grammar parser reads it, so a bad
+/// grammar is reported with its position instead of failing inside the sampler.
+pub fn validate(grammar: &str) -> Result<(), GrammarError> {
+    let mut parser = Parser {
+        src: grammar.as_bytes(),
+        pos: 0,
+        defined: HashSet::new(),
+        referenced: vec![],
+    };
+
+    parser.skip_space(true);
+    while !parser.at_end() {
+        parser.parse_rule()?;
+    }
+
+    if !parser.defined.contains("root") {
+        return Err(GrammarError::MissingRoot);
+    }
+    for name in parser.referenced {
+        if !parser.defined.contains(&name) {
+            return Err(GrammarError::UndefinedRule(name));
+        }
+    }
+
+    Ok(())
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    defined: HashSet<String>,
+    referenced: Vec<String>,
+}
+
+impl Parser<'_> {
+    fn at_end(&self) -> bool {
+        self.pos >= self.src.len()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, GrammarError> {
+        let consumed = &self.src[..self.pos.min(self.src.len())];
+        let line = consumed.iter().filter(|&&c| c == b'\n').count() + 1;
+        let line_start = consumed
+            .iter()
+            .rposition(|&c| c == b'\n')
+            .map_or(0, |i| i + 1);
+
+        Err(GrammarError::Syntax {
+            line,
+            column: String::from_utf8_lossy(&consumed[line_start..])
+                .chars()
+                .count()
+                + 1,
+            message: message.to_string(),
+        })
+    }
+
+    fn skip_space(&mut self, newline_ok: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                b' ' | b'\t' => self.pos += 1,
+                b'#' => {
+                    while !matches!(self.peek(), None | Some(b'\n') | Some(b'\r')) {
+                        self.pos += 1;
+                    }
+                }
+                b'\r' | b'\n' if newline_ok => self.pos += 1,
+                _ => break,
+            }
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<String, GrammarError> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == b'-')
+        {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return self.error("expecting name");
+        }
+
+        Ok(String::from_utf8_lossy(&self.src[start..self.pos]).to_string())
+    }
+
+    fn parse_rule(&mut self) -> Result<(), GrammarError> {
+        let name = self.parse_name()?;
+        self.skip_space(false);
+        if !self.src[self.pos..].starts_with(b"::=") {
+            return self.error("expecting ::=");
+        }
+        self.pos += 3;
+        self.skip_space(true);
+
+        self.parse_alternates(false)?;
+
+        match self.peek() {
+            None | Some(b'\n') | Some(b'\r') => {}
+            Some(_) => return self.error("expecting newline or end"),
+        }
+        self.skip_space(true);
+
+        self.defined.insert(name);
+        Ok(())
+    }
+
+    fn parse_alternates(&mut self, nested: bool) -> Result<(), GrammarError> {
+        self.parse_sequence(nested)?;
+        while self.peek() == Some(b'|') {
+            self.pos += 1;
+            self.skip_space(true);
+            self.parse_sequence(nested)?;
+        }
+        Ok(())
+    }
+
+    fn parse_sequence(&mut self, nested: bool) -> Result<(), GrammarError> {
+        let mut has_item = false;
+        while let Some(c) = self.peek() {
+            match c {
+                b'"' => {
+                    self.pos += 1;
+                    while self.peek() != Some(b'"') {
+                        self.parse_char()?;
+                    }
+                    self.pos += 1;
+                }
+                b'[' => {
+                    self.pos += 1;
+                    if self.peek() == Some(b'^') {
+                        self.pos += 1;
+                    }
+                    while self.peek() != Some(b']') {
+                        self.parse_char()?;
+                        if self.peek() == Some(b'-') && self.src.get(self.pos + 1) != Some(&b']') {
+                            self.pos += 1;
+                            self.parse_char()?;
+                        }
+                    }
+                    self.pos += 1;
+                }
+                b'(' => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    self.parse_alternates(true)?;
+                    if self.peek() != Some(b')') {
+                        return self.error("expecting ')'");
+                    }
+                    self.pos += 1;
+                }
+                b'*' | b'+' | b'?' => {
+                    if !has_item {
+                        return self.error("expecting preceding item to */+/?");
+                    }
+                    self.pos += 1;
+                }
+                c if c.is_ascii_alphanumeric() || c == b'-' => {
+                    let name = self.parse_name()?;
+                    self.referenced.push(name);
+                }
+                _ => break,
+            }
+            has_item = true;
+            self.skip_space(nested);
+        }
+        Ok(())
+    }
+
+    // Consumes one, possibly escaped, character of a literal or character class.
+    fn parse_char(&mut self) -> Result<(), GrammarError> {
+        match self.peek() {
+            None => self.error("unexpected end of input"),
+            Some(b'\\') => {
+                self.pos += 1;
+                let digits = match self.peek() {
+                    Some(b'x') => 2,
+                    Some(b'u') => 4,
+                    Some(b'U') => 8,
+                    Some(b't' | b'r' | b'n' | b'\\' | b'"' | b'[' | b']') => 0,
+                    None => return self.error("unexpected end of input"),
+                    Some(_) => return self.error("unknown escape"),
+                };
+                self.pos += 1;
+                for _ in 0..digits {
+                    if !self.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+                        return self.error("expecting hex digit");
+                    }
+                    self.pos += 1;
+                }
+                Ok(())
+            }
+            Some(_) => {
+                self.pos += 1;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syntax_error(grammar: &str) -> (usize, usize, String) {
+        match validate(grammar) {
+            Err(GrammarError::Syntax {
+                line,
+                column,
+                message,
+            }) => (line, column, message),
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn accepts_valid_grammars() {
+        validate(r#"root ::= "yes" | "no""#).unwrap();
+        validate(
+            r#"# a comment
+root ::= item ("," ws item)*   # trailing comment
+item ::= [a-z\x2D]+ | "\"" [^"\\]* "\""
+ws ::= [ \t\n]*
+"#,
+        )
+        .unwrap();
+        validate("root ::= (\n  \"a\"\n  | \"b\"\n)").unwrap();
+    }
+
+    #[test]
+    fn reports_missing_and_undefined_rules() {
+        assert_eq!(validate(r#"item ::= "a""#), Err(GrammarError::MissingRoot));
+        assert_eq!(
+            validate("root ::= item"),
+            Err(GrammarError::UndefinedRule("item".to_string()))
+        );
+    }
+
+    #[test]
+    fn reports_the_position_of_syntax_errors() {
+        assert_eq!(
+            syntax_error("root ::= \"a\"\nitem = \"b\""),
+            (2, 6, "expecting ::=".to_string())
+        );
+        assert_eq!(
+            syntax_error(r#"root ::= ("a" "b""#),
+            (1, 18, "expecting ')'".to_string())
+        );
+        assert_eq!(
+            syntax_error("root ::= * \"a\""),
+            (1, 10, "expecting preceding item to */+/?".to_string())
+        );
+        assert_eq!(
+            syntax_error(r#"root ::= "\q""#),
+            (1, 12, "unknown escape".to_string())
+        );
+        assert_eq!(
+            syntax_error(r#"root ::= [\x4]"#),
+            (1, 14, "expecting hex digit".to_string())
+        );
+        assert_eq!(
+            syntax_error(r#"root ::= "a"#),
+            (1, 12, "unexpected end of input".to_string())
+        );
+    }
+
+    #[test]
+    fn columns_count_characters() {
+        assert_eq!(
+            syntax_error("root ::= \"é\" }"),
+            (1, 14, "expecting newline or end".to_string())
+        );
+    }
+}
@@ -0,0 +1,840 @@
+use serde_json::Value;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum SchemaError {
+    #[error("unsupported schema construct at {path}: {what}")]
+    Unsupported { path: String, what: String },
+    #[error("invalid pattern {pattern:?}: {message}")]
+    InvalidPattern { pattern: String, message: String },
+    #[error("invalid bounds at {path}: {min_key} {min} is above {max_key} {max}")]
+    InvalidBounds {
+        path: String,
+        min_key: &'static str,
+        min: u64,
+        max_key: &'static str,
+        max: u64,
+    },
+}
+
+const PRIMITIVE_RULES: &[(&str, &str)] = &[
+    ("ws", r#"[ \t\n]*"#),
+    (
+        "char",
+        r#"[^"\\\x7F\x00-\x1F] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F])"#,
+    ),
+    ("string", r#""\"" char* "\"" ws"#),
+    (
+        "number",
+        r#""-"? ([0-9] | [1-9] [0-9]*) ("." [0-9]+)? ([eE] [-+]? [0-9]+)? ws"#,
+    ),
+    ("integer", r#""-"? ([0-9] | [1-9] [0-9]*) ws"#),
+    ("boolean", r#"("true" | "false") ws"#),
+    ("null", r#""null" ws"#),
+    (
+        "value",
+        r#"object | array | string | number | boolean | null"#,
+    ),
+    (
+        "object",
+        r#""{" ws ( string ":" ws value ("," ws string ":" ws value)* )? "}" ws"#,
+    ),
+    ("array", r#""[" ws ( value ("," ws value)* )? "]" ws"#),
+];
+
+/// Compiles a JSON Schema into a GBNF grammar whose `root` only accepts
+/// matching JSON documents.
+///
+/// Supported: `type` (including lists of types), `properties` with `required`,
+/// `items` with `minItems`/`maxItems`, `enum`, `const`, `oneOf`/`anyOf` and
+/// string `pattern`s written in a regex subset (literals, classes, `.`, groups,
+/// alternation and the `* + ? {n,m}` quantifiers). Properties are emitted
+/// required first, in schema order.
+pub fn schema_to_grammar(schema: &Value) -> Result<String, SchemaError> {
+    let mut compiler = Compiler { rules: vec![] };
+    let root = compiler.visit(schema, "root")?;
+    compiler.add_rule("root", root);
+
+    let mut grammar = String::new();
+    for (name, body) in &compiler.rules {
+        grammar.push_str(&format!("{} ::= {}\n", name, body));
+    }
+    for (name, body) in PRIMITIVE_RULES {
+        grammar.push_str(&format!("{} ::= {}\n", name, body));
+    }
+
+    Ok(grammar)
+}
+
+struct Compiler {
+    rules: Vec<(String, String)>,
+}
+
+impl Compiler {
+    fn add_rule(&mut self, name: &str, body: String) -> String {
+        let mut unique = name.to_string();
+        let mut i = 1;
+        while self.rules.iter().any(|(n, _)| *n == unique)
+            || PRIMITIVE_RULES.iter().any(|(n, _)| *n == unique)
+        {
+            unique = format!("{}{}", name, i);
+            i += 1;
+        }
+        self.rules.push((unique.clone(), body));
+        unique
+    }
+
+    // Returns a GBNF expression for `schema`, adding helper rules named after `path`.
+    fn visit(&mut self, schema: &Value, path: &str) -> Result<String, SchemaError> {
+        let unsupported = |what: &str| SchemaError::Unsupported {
+            path: path.to_string(),
+            what: what.to_string(),
+        };
+
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".to_string()),
+            Value::Object(map) => map,
+            _ => return Err(unsupported("schema must be an object")),
+        };
+
+        if schema.contains_key("$ref") {
+            return Err(unsupported("$ref"));
+        }
+
+        if let Some(value) = schema.get("const") {
+            return Ok(json_literal(value));
+        }
+
+        if let Some(values) = schema.get("enum") {
+            let values = values
+                .as_array()
+                .ok_or_else(|| unsupported("enum must be an array"))?;
+            let alternatives: Vec<String> = values.iter().map(json_literal).collect();
+            return Ok(format!("({})", alternatives.join(" | ")));
+        }
+
+        for key in ["oneOf", "anyOf"] {
+            if let Some(schemas) = schema.get(key) {
+                let schemas = schemas
+                    .as_array()
+                    .ok_or_else(|| unsupported(&format!("{} must be an array", key)))?;
+                let mut alternatives = vec![];
+                for (i, s) in schemas.iter().enumerate() {
+                    let expr = self.visit(s, &format!("{}-{}", path, i))?;
+                    alternatives.push(self.add_rule(&format!("{}-{}", path, i), expr));
+                }
+                return Ok(format!("({})", alternatives.join(" | ")));
+            }
+        }
+
+        match schema.get("type") {
+            None => {
+                if schema.contains_key("properties") {
+                    self.visit_object(schema, path)
+                } else {
+                    Ok("value".to_string())
+                }
+            }
+            Some(Value::Array(types)) => {
+                let mut alternatives = vec![];
+                for t in types {
+                    let mut single = schema.clone();
+                    single.insert("type".to_string(), t.clone());
+                    alternatives.push(self.visit(&Value::Object(single), path)?);
+                }
+                Ok(format!("({})", alternatives.join(" | ")))
+            }
+            Some(Value::String(t)) => match t.as_str() {
+                "object" => self.visit_object(schema, path),
+                "array" => self.visit_array(schema, path),
+                "string" => self.visit_string(schema, path),
+                "number" | "integer" | "boolean" | "null" => Ok(t.clone()),
+                other => Err(unsupported(&format!("type {}", other))),
+            },
+            Some(_) => Err(unsupported("type must be a string or an array")),
+        }
+    }
+
+    fn visit_object(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        path: &str,
+    ) -> Result<String, SchemaError> {
+        let properties = match schema.get("properties").and_then(Value::as_object) {
+            Some(properties) if !properties.is_empty() => properties,
+            _ => return Ok("object".to_string()),
+        };
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|r| r.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        let mut required_props = vec![];
+        let mut optional_props = vec![];
+        for (name, prop_schema) in properties {
+            let rule_name = format!("{}-{}", path, sanitize(name));
+            let expr = self.visit(prop_schema, &rule_name)?;
+            let value_rule = self.add_rule(&rule_name, expr);
+            let kv = format!(
+                "{} \":\" ws {}",
+                json_literal(&Value::String(name.clone())),
+                value_rule
+            );
+            let kv_rule = self.add_rule(&format!("{}-kv", rule_name), kv);
+            if required.contains(&name.as_str()) {
+                required_props.push(kv_rule);
+            } else {
+                optional_props.push(kv_rule);
+            }
+        }
+
+        let mut body = String::from("\"{\" ws ");
+        if !required_props.is_empty() {
+            body.push_str(&required_props.join(" \",\" ws "));
+            for prop in &optional_props {
+                body.push_str(&format!(" (\",\" ws {})?", prop));
+            }
+        } else {
+            // any in-order subset of the optional properties, without a leading comma
+            let mut alternatives = vec![];
+            for (i, prop) in optional_props.iter().enumerate() {
+                let mut alternative = prop.clone();
+                for next in &optional_props[i + 1..] {
+                    alternative.push_str(&format!(" (\",\" ws {})?", next));
+                }
+                alternatives.push(alternative);
+            }
+            body.push_str(&format!("({})?", alternatives.join(" | ")));
+        }
+        body.push_str(" \"}\" ws");
+
+        Ok(body)
+    }
+
+    fn visit_array(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        path: &str,
+    ) -> Result<String, SchemaError> {
+        let item = match schema.get("items") {
+            Some(items) => {
+                let expr = self.visit(items, &format!("{}-item", path))?;
+                self.add_rule(&format!("{}-item", path), expr)
+            }
+            None => "value".to_string(),
+        };
+        let (min, max) = bounds(schema, path, "minItems", "maxItems")?;
+
+        let items = repeat(&item, &format!("\",\" ws {}", item), min, max);
+        Ok(format!("\"[\" ws {} \"]\" ws", items))
+    }
+
+    fn visit_string(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        path: &str,
+    ) -> Result<String, SchemaError> {
+        if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+            let expr = PatternParser::new(pattern).parse()?;
+            return Ok(format!("\"\\\"\" {} \"\\\"\" ws", expr));
+        }
+
+        if !schema.contains_key("minLength") && !schema.contains_key("maxLength") {
+            return Ok("string".to_string());
+        }
+
+        let (min, max) = bounds(schema, path, "minLength", "maxLength")?;
+        let chars = repeat("char", "char", min, max);
+        Ok(format!("\"\\\"\" {} \"\\\"\" ws", chars))
+    }
+}
+
+// The `min_key` and `max_key` counts of `schema`, 0 and unbounded by default.
+fn bounds(
+    schema: &serde_json::Map<String, Value>,
+    path: &str,
+    min_key: &'static str,
+    max_key: &'static str,
+) -> Result<(usize, Option<usize>), SchemaError> {
+    let min = schema.get(min_key).and_then(Value::as_u64).unwrap_or(0);
+    let max = schema.get(max_key).and_then(Value::as_u64);
+    if let Some(max) = max.filter(|&max| min > max) {
+        return Err(SchemaError::InvalidBounds {
+            path: path.to_string(),
+            min_key,
+            min,
+            max_key,
+            max,
+        });
+    }
+
+    Ok((min as usize, max.map(|max| max as usize)))
+}
+
+// `first (rest)*` bounded to between `min` and `max` occurrences in total.
+fn repeat(first: &str, rest: &str, min: usize, max: Option<usize>) -> String {
+    if max == Some(0) {
+        return String::new();
+    }
+
+    let mut expr = first.to_string();
+    for _ in 1..min.max(1) {
+        expr.push_str(&format!(" {}", rest));
+    }
+    match max {
+        None => expr.push_str(&format!(" ({})*", rest)),
+        Some(max) => {
+            let mut tail = String::new();
+            for _ in min.max(1)..max {
+                tail = format!(" ({}{})?", rest, tail);
+            }
+            expr.push_str(&tail);
+        }
+    }
+
+    if min == 0 {
+        format!("({})?", expr)
+    } else {
+        expr
+    }
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+// GBNF literal matching the JSON serialization of `value`, followed by whitespace.
+fn json_literal(value: &Value) -> String {
+    format!("{} ws", gbnf_literal(&value.to_string()))
+}
+
+fn gbnf_literal(text: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Translates the supported regex subset of a string `pattern` into a GBNF
+/// expression over the JSON-escaped string content.
+struct PatternParser {
+    pattern: String,
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl PatternParser {
+    fn new(pattern: &str) -> Self {
+        let trimmed = pattern.strip_prefix('^').unwrap_or(pattern);
+        let trimmed = if trimmed.ends_with('$') && !trimmed.ends_with("\\$") {
+            &trimmed[..trimmed.len() - 1]
+        } else {
+            trimmed
+        };
+
+        Self {
+            pattern: pattern.to_string(),
+            chars: trimmed.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, SchemaError> {
+        Err(SchemaError::InvalidPattern {
+            pattern: self.pattern.clone(),
+            message: message.to_string(),
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn parse(mut self) -> Result<String, SchemaError> {
+        let expr = self.parse_alternation()?;
+        if self.pos < self.chars.len() {
+            return self.error("unbalanced ')'");
+        }
+        Ok(expr)
+    }
+
+    fn parse_alternation(&mut self) -> Result<String, SchemaError> {
+        let mut alternatives = vec![self.parse_sequence()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alternatives.push(self.parse_sequence()?);
+        }
+        Ok(format!("({})", alternatives.join(" | ")))
+    }
+
+    fn parse_sequence(&mut self) -> Result<String, SchemaError> {
+        let mut items = vec![];
+        while let Some(c) = self.peek() {
+            let atom = match c {
+                '|' | ')' => break,
+                '(' => {
+                    self.pos += 1;
+                    if self.chars[self.pos..].starts_with(&['?', ':']) {
+                        self.pos += 2;
+                    }
+                    let group = self.parse_alternation()?;
+                    if self.peek() != Some(')') {
+                        return self.error("missing ')'");
+                    }
+                    self.pos += 1;
+                    group
+                }
+                '[' => self.parse_class()?,
+                '.' => {
+                    self.pos += 1;
+                    "char".to_string()
+                }
+                '\\' => {
+                    self.pos += 1;
+                    match self.parse_escape()? {
+                        Escape::Char(c) => gbnf_literal(&json_escape(c)),
+                        Escape::Class(class) => render_class(class, false),
+                    }
+                }
+                '*' | '+' | '?' | '{' => return self.error("quantifier without a preceding item"),
+                c => {
+                    self.pos += 1;
+                    gbnf_literal(&json_escape(c))
+                }
+            };
+            items.push(self.parse_quantifier(atom)?);
+        }
+        Ok(items.join(" "))
+    }
+
+    fn parse_quantifier(&mut self, atom: String) -> Result<String, SchemaError> {
+        match self.peek() {
+            Some(q @ ('*' | '+' | '?')) => {
+                self.pos += 1;
+                Ok(format!("{}{}", atom, q))
+            }
+            Some('{') => {
+                let close = match self.chars[self.pos..].iter().position(|&c| c == '}') {
+                    Some(i) => self.pos + i,
+                    None => return self.error("missing '}'"),
+                };
+                let spec: String = self.chars[self.pos + 1..close].iter().collect();
+                self.pos = close + 1;
+
+                let parse = |s: &str| s.trim().parse::<usize>().ok();
+                let (min, max) = match spec.split_once(',') {
+                    None => match parse(&spec) {
+                        Some(n) => (n, Some(n)),
+                        None => return self.error("invalid repetition"),
+                    },
+                    Some((min, max)) => match (parse(min), max.trim()) {
+                        (Some(min), "") => (min, None),
+                        (Some(min), max) => match parse(max) {
+                            Some(max) if max >= min => (min, Some(max)),
+                            _ => return self.error("invalid repetition"),
+                        },
+                        _ => return self.error("invalid repetition"),
+                    },
+                };
+                Ok(format!("({})", repeat(&atom, &atom, min, max)))
+            }
+            _ => Ok(atom),
+        }
+    }
+
+    fn parse_class(&mut self) -> Result<String, SchemaError> {
+        self.pos += 1;
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut items = vec![];
+        loop {
+            let start = match self.peek() {
+                None => return self.error("missing ']'"),
+                Some(']') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(_) => self.parse_class_member()?,
+            };
+            let is_range = self.peek() == Some('-')
+                && !matches!(self.chars.get(self.pos + 1), None | Some(']'));
+            match start {
+                Escape::Char(start) if is_range => {
+                    self.pos += 1;
+                    match self.parse_class_member()? {
+                        Escape::Char(end) if end >= start => items.push((start, end)),
+                        _ => return self.error("invalid range"),
+                    }
+                }
+                Escape::Char(c) => items.push((c, c)),
+                Escape::Class(_) if is_range => return self.error("invalid range"),
+                Escape::Class(class) => items.extend_from_slice(class),
+            }
+        }
+        if items.is_empty() {
+            return self.error("empty class");
+        }
+
+        Ok(render_class(&items, negated))
+    }
+
+    fn parse_class_member(&mut self) -> Result<Escape, SchemaError> {
+        let c = self.chars[self.pos];
+        self.pos += 1;
+        if c == '\\' {
+            self.parse_escape()
+        } else {
+            Ok(Escape::Char(c))
+        }
+    }
+
+    // Parses the character after a backslash.
+    fn parse_escape(&mut self) -> Result<Escape, SchemaError> {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return self.error("trailing backslash"),
+        };
+        self.pos += 1;
+
+        Ok(match c {
+            'd' => Escape::Class(DIGIT),
+            'w' => Escape::Class(WORD),
+            's' => Escape::Class(SPACE),
+            'D' | 'W' | 'S' | 'b' | 'B' => return self.error("unsupported escape"),
+            'n' => Escape::Char('\n'),
+            't' => Escape::Char('\t'),
+            'r' => Escape::Char('\r'),
+            c => Escape::Char(c),
+        })
+    }
+}
+
+enum Escape {
+    Char(char),
+    Class(&'static [(char, char)]),
+}
+
+const DIGIT: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+const SPACE: &[(char, char)] = &[(' ', ' '), ('\t', '\r')];
+
+// Characters JSON strings only hold escaped, as sorted inclusive ranges.
+// Classes are lists of inclusive ranges too, single characters having both
+// ends the same.
+const JSON_ESCAPED: &[(char, char)] = &[('\x00', '\x1F'), ('"', '"'), ('\\', '\\')];
+
+// Escaped characters a negated class still matches, when it doesn't list them.
+// Other control characters are left out to keep the grammar small.
+const NEGATED_ESCAPES: &[char] = &['"', '\\', '\n', '\r', '\t'];
+
+// A class over the JSON-escaped string content. Characters JSON escapes can't
+// be class members, so they become alternatives matching their escape
+// sequences, e.g. `[a"]` becomes `([a] | "\\\"")`.
+fn render_class(items: &[(char, char)], negated: bool) -> String {
+    let contains = |c: char| items.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c));
+
+    let mut class = String::from("[");
+    let escaped: Vec<char> = if negated {
+        class.push_str("^\"\\\\\\x00-\\x1F");
+        for &(lo, hi) in items {
+            push_range(&mut class, lo, hi);
+        }
+        NEGATED_ESCAPES
+            .iter()
+            .copied()
+            .filter(|&c| !contains(c))
+            .collect()
+    } else {
+        for &(lo, hi) in items {
+            for (lo, hi) in unescaped_ranges(lo, hi) {
+                push_range(&mut class, lo, hi);
+            }
+        }
+        JSON_ESCAPED
+            .iter()
+            .flat_map(|&(lo, hi)| lo..=hi)
+            .filter(|&c| contains(c))
+            .collect()
+    };
+    class.push(']');
+
+    let mut alternatives = vec![];
+    if class != "[]" {
+        alternatives.push(class);
+    }
+    alternatives.extend(escaped.into_iter().map(|c| gbnf_literal(&json_escape(c))));
+    if alternatives.len() == 1 {
+        alternatives.remove(0)
+    } else {
+        format!("({})", alternatives.join(" | "))
+    }
+}
+
+// Splits `lo..=hi` around the characters JSON escapes.
+fn unescaped_ranges(lo: char, hi: char) -> Vec<(char, char)> {
+    let (mut start, hi) = (lo as u32, hi as u32);
+    let mut ranges = vec![];
+    for &(escaped_lo, escaped_hi) in JSON_ESCAPED {
+        let (escaped_lo, escaped_hi) = (escaped_lo as u32, escaped_hi as u32);
+        if start > hi {
+            break;
+        }
+        if escaped_hi < start {
+            continue;
+        }
+        if escaped_lo > start {
+            ranges.push((start, (escaped_lo - 1).min(hi)));
+        }
+        start = start.max(escaped_hi + 1);
+    }
+    if start <= hi {
+        ranges.push((start, hi));
+    }
+    ranges
+        .into_iter()
+        .filter_map(|(lo, hi)| Some((char::from_u32(lo)?, char::from_u32(hi)?)))
+        .collect()
+}
+
+fn push_range(class: &mut String, lo: char, hi: char) {
+    class.push_str(&class_char(lo));
+    if hi != lo {
+        class.push('-');
+        class.push_str(&class_char(hi));
+    }
+}
+
+fn json_escape(c: char) -> String {
+    serde_json::to_string(&c.to_string())
+        .map(|s| s[1..s.len() - 1].to_string())
+        .unwrap_or_default()
+}
+
+fn class_char(c: char) -> String {
+    match c {
+        '\\' | ']' | '[' | '"' => format!("\\{}", c),
+        '-' | '^' => format!("\\x{:02X}", c as u32),
+        c if c.is_ascii_control() => format!("\\x{:02X}", c as u32),
+        c => c.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::llama::grammar;
+
+    // Compiles `schema`, checks llama.cpp would parse the grammar and returns
+    // its rules by name.
+    fn compile(schema: Value) -> Vec<(String, String)> {
+        let grammar = schema_to_grammar(&schema).unwrap();
+        grammar::validate(&grammar).unwrap();
+        grammar
+            .lines()
+            .map(|line| {
+                let (name, body) = line.split_once(" ::= ").unwrap();
+                (name.to_string(), body.to_string())
+            })
+            .collect()
+    }
+
+    fn root(schema: Value) -> String {
+        compile(schema).remove(0).1
+    }
+
+    fn pattern(pattern: &str) -> String {
+        let body = root(json!({ "type": "string", "pattern": pattern }));
+        body.strip_prefix(r#""\"" "#)
+            .and_then(|body| body.strip_suffix(r#" "\"" ws"#))
+            .unwrap()
+            .to_string()
+    }
+
+    fn pattern_error(pattern: &str) -> String {
+        match schema_to_grammar(&json!({ "type": "string", "pattern": pattern })) {
+            Err(SchemaError::InvalidPattern { message, .. }) => message,
+            other => panic!("expected an invalid pattern, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn primitives() {
+        assert_eq!(root(json!({ "type": "integer" })), "integer");
+        assert_eq!(root(json!({ "type": "string" })), "string");
+        assert_eq!(root(json!(true)), "value");
+        assert_eq!(
+            root(json!({ "type": ["string", "null"] })),
+            "(string | null)"
+        );
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(root(json!({ "const": "a\"b" })), r#""\"a\\\"b\"" ws"#);
+        assert_eq!(
+            root(json!({ "enum": [1, "x", null] })),
+            r#"("1" ws | "\"x\"" ws | "null" ws)"#
+        );
+    }
+
+    #[test]
+    fn object_puts_required_properties_first() {
+        let rules = compile(json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer" },
+            },
+            "required": ["age"],
+        }));
+        let rule = |name: &str| &rules.iter().find(|(n, _)| n == name).unwrap().1;
+
+        assert_eq!(rule("root-name-kv"), r#""\"name\"" ws ":" ws root-name"#);
+        assert_eq!(rule("root-age"), "integer");
+        assert_eq!(
+            rule("root"),
+            r#""{" ws root-age-kv ("," ws root-name-kv)? "}" ws"#
+        );
+    }
+
+    #[test]
+    fn object_without_required_properties() {
+        let rules = compile(json!({
+            "properties": { "a": {}, "b": {} },
+        }));
+        assert_eq!(
+            rules[rules.len() - 1 - PRIMITIVE_RULES.len()].1,
+            r#""{" ws (root-a-kv ("," ws root-b-kv)? | root-b-kv)? "}" ws"#
+        );
+    }
+
+    #[test]
+    fn array_bounds() {
+        let rules = compile(json!({
+            "type": "array",
+            "items": { "type": "number" },
+            "minItems": 1,
+            "maxItems": 3,
+        }));
+        assert_eq!(rules[0], ("root-item".to_string(), "number".to_string()));
+        assert_eq!(
+            rules[1].1,
+            r#""[" ws root-item ("," ws root-item ("," ws root-item)?)? "]" ws"#
+        );
+    }
+
+    #[test]
+    fn string_length() {
+        assert_eq!(
+            root(json!({ "type": "string", "minLength": 2, "maxLength": 3 })),
+            r#""\"" char char (char)? "\"" ws"#
+        );
+    }
+
+    #[test]
+    fn pattern_literals_are_json_escaped() {
+        assert_eq!(pattern("^ab$"), r#"("a" "b")"#);
+        assert_eq!(pattern(r#"a"\\"#), r#"("a" "\\\"" "\\\\")"#);
+        assert_eq!(pattern(r"\n|\t"), r#"("\\n" | "\\t")"#);
+        assert_eq!(pattern(r"a{2,}"), r#"(("a" "a" ("a")*))"#);
+    }
+
+    #[test]
+    fn pattern_classes() {
+        assert_eq!(pattern(r"[a-c_]+"), "([a-c_]+)");
+        assert_eq!(pattern(r"\d"), "([0-9])");
+        assert_eq!(pattern(r"[\w-]"), r"([a-zA-Z0-9_\x2D])");
+        assert_eq!(pattern("."), "(char)");
+    }
+
+    #[test]
+    fn pattern_classes_escape_json_characters() {
+        assert_eq!(pattern(r#"[a"]"#), r#"(([a] | "\\\""))"#);
+        assert_eq!(pattern(r#"["\\]"#), r#"(("\\\"" | "\\\\"))"#);
+        assert_eq!(pattern(r"[ -~]"), r#"(([ -!#-\[\]-~] | "\\\"" | "\\\\"))"#);
+        assert_eq!(
+            pattern(r"\s"),
+            r#"(([ ] | "\\t" | "\\n" | "\\u000b" | "\\f" | "\\r"))"#
+        );
+        assert_eq!(pattern(r"[\n]"), r#"("\\n")"#);
+    }
+
+    #[test]
+    fn negated_classes_match_escaped_characters_they_do_not_list() {
+        assert_eq!(
+            pattern(r"[^a]"),
+            r#"(([^"\\\x00-\x1Fa] | "\\\"" | "\\\\" | "\\n" | "\\r" | "\\t"))"#
+        );
+        assert_eq!(
+            pattern(r#"[^"\s]"#),
+            r#"(([^"\\\x00-\x1F\" \x09-\x0D] | "\\\\"))"#
+        );
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert_eq!(pattern_error("(a"), "missing ')'");
+        assert_eq!(pattern_error("a)"), "unbalanced ')'");
+        assert_eq!(pattern_error("*a"), "quantifier without a preceding item");
+        assert_eq!(pattern_error("[ab"), "missing ']'");
+        assert_eq!(pattern_error("[z-a]"), "invalid range");
+        assert_eq!(pattern_error(r"[\d-z]"), "invalid range");
+        assert_eq!(pattern_error(r"\b"), "unsupported escape");
+        assert_eq!(pattern_error("a{3,1}"), "invalid repetition");
+    }
+
+    #[test]
+    fn unsupported_constructs() {
+        assert!(matches!(
+            schema_to_grammar(&json!({ "$ref": "#/defs/a" })),
+            Err(SchemaError::Unsupported { .. })
+        ));
+        assert!(matches!(
+            schema_to_grammar(&json!({ "type": "tuple" })),
+            Err(SchemaError::Unsupported { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_minimums_above_maximums() {
+        assert!(matches!(
+            schema_to_grammar(&json!({ "type": "array", "minItems": 3, "maxItems": 2 })),
+            Err(SchemaError::InvalidBounds {
+                min_key: "minItems",
+                min: 3,
+                max: 2,
+                ..
+            })
+        ));
+        assert!(matches!(
+            schema_to_grammar(&json!({ "type": "string", "minLength": 5, "maxLength": 1 })),
+            Err(SchemaError::InvalidBounds {
+                min_key: "minLength",
+                min: 5,
+                max: 1,
+                ..
+            })
+        ));
+        // equal bounds are an exact count
+        assert_eq!(
+            root(json!({ "type": "string", "minLength": 2, "maxLength": 2 })),
+            r#""\"" char char "\"" ws"#
+        );
+    }
+}
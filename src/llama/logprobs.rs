@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+
+pub type LogprobsCallback = Box<dyn Fn(&TokenLogprobs) + Send + 'static>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenLogprob {
+    pub id: i32,
+    pub text: String,
+    pub logprob: f32,
+}
+
+/// Log-probability of a generated token and the most likely alternatives at
+/// that position. Both are taken from the distribution after penalties and
+/// grammar, but before temperature and the truncating samplers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenLogprobs {
+    pub token: TokenLogprob,
+    pub top: Vec<TokenLogprob>,
+}
+
+impl TokenLogprobs {
+    pub fn prob(&self) -> f32 {
+        self.token.logprob.exp()
+    }
+}
+
+// Drops the logprobs of the tokens after the first `len` bytes of their text,
+// which a stop string or condition cut from the output. A token cut partway
+// keeps its logprob, since part of it is in the output.
+pub(crate) fn trim_logprobs(logprobs: &mut Vec<TokenLogprobs>, len: usize) {
+    let mut start = 0;
+    logprobs.retain(|logprobs| {
+        let keep = start < len;
+        start += logprobs.token.text.len();
+        keep
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(texts: &[&str]) -> Vec<TokenLogprobs> {
+        texts
+            .iter()
+            .enumerate()
+            .map(|(id, text)| TokenLogprobs {
+                token: TokenLogprob {
+                    id: id as i32,
+                    text: text.to_string(),
+                    logprob: -1.0,
+                },
+                top: vec![],
+            })
+            .collect()
+    }
+
+    fn texts(logprobs: &[TokenLogprobs]) -> Vec<&str> {
+        logprobs.iter().map(|l| l.token.text.as_str()).collect()
+    }
+
+    #[test]
+    fn trims_tokens_after_the_output() {
+        let mut logprobs = tokens(&["Hello", " world", "\n", "User", ":"]);
+        trim_logprobs(&mut logprobs, "Hello world".len());
+        assert_eq!(texts(&logprobs), ["Hello", " world"]);
+    }
+
+    #[test]
+    fn keeps_a_token_cut_partway() {
+        let mut logprobs = tokens(&["Hello", " wor", "ld!"]);
+        trim_logprobs(&mut logprobs, "Hello world".len());
+        assert_eq!(texts(&logprobs), ["Hello", " wor", "ld!"]);
+    }
+
+    #[test]
+    fn trims_everything_for_an_empty_output() {
+        let mut logprobs = tokens(&["STOP"]);
+        trim_logprobs(&mut logprobs, 0);
+        assert!(logprobs.is_empty());
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    ops::Deref,
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use super::{error::LlamaError, options::ModelOptions, LLama};
+use crate::LOGGER;
+
+/// Whether the model of a slot is in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelState {
+    Unloaded,
+    Loading,
+    Loaded,
+}
+
+/// A model file and its options, loaded on first use and unloaded again after
+/// it's been idle.
+pub struct ModelSlot {
+    path: String,
+    opts: ModelOptions,
+    // Held while loading, so that concurrent requests don't load the model twice.
+    llama: tokio::sync::Mutex<Option<Arc<LLama>>>,
+    state: Mutex<ModelState>,
+    // Fraction of the model loaded, while it's loading.
+    progress: Arc<Mutex<f32>>,
+    // Set when a request takes the model and again when it's done with it.
+    last_used: Arc<Mutex<Instant>>,
+    // Models unloaded while requests still used them, in memory until those finish.
+    retired: Mutex<Vec<Weak<LLama>>>,
+}
+
+impl ModelSlot {
+    pub fn new(path: String, opts: ModelOptions) -> Self {
+        Self {
+            path,
+            opts,
+            llama: tokio::sync::Mutex::new(None),
+            state: Mutex::new(ModelState::Unloaded),
+            progress: Arc::new(Mutex::new(0.0)),
+            last_used: Arc::new(Mutex::new(Instant::now())),
+            retired: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn options(&self) -> &ModelOptions {
+        &self.opts
+    }
+
+    pub fn state(&self) -> ModelState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Fraction of the model loaded so far, `None` when it isn't loading.
+    pub fn loading_progress(&self) -> Option<f32> {
+        (self.state() == ModelState::Loading).then(|| *self.progress.lock().unwrap())
+    }
+
+    pub fn last_used(&self) -> Instant {
+        *self.last_used.lock().unwrap()
+    }
+
+    /// Copies of the model unloaded while requests still used them, which stay
+    /// in memory until those requests finish.
+    pub fn retired_copies(&self) -> usize {
+        let mut retired = self.retired.lock().unwrap();
+        retired.retain(|llama| llama.strong_count() > 0);
+        retired.len()
+    }
+
+    /// The model if it's loaded, without loading it.
+    pub async fn loaded(&self) -> Option<ModelGuard> {
+        let llama = self.llama.lock().await;
+        llama.clone().map(|llama| self.guard(llama))
+    }
+
+    fn guard(&self, llama: Arc<LLama>) -> ModelGuard {
+        *self.last_used.lock().unwrap() = Instant::now();
+        ModelGuard {
+            llama,
+            last_used: self.last_used.clone(),
+        }
+    }
+
+    /// The model, loaded on a blocking thread first if it isn't loaded.
+    pub async fn get(&self) -> Result<ModelGuard, LlamaError> {
+        let mut llama = self.llama.lock().await;
+        if let Some(llama) = &*llama {
+            return Ok(self.guard(llama.clone()));
+        }
+
+        *self.state.lock().unwrap() = ModelState::Loading;
+        slog::info!(LOGGER, "loading model {}", self.path);
+        let started = Instant::now();
+        let (path, opts) = (self.path.clone(), self.opts.clone());
+        let progress = self.progress.clone();
+        *progress.lock().unwrap() = 0.0;
+        let loaded = tokio::task::spawn_blocking(move || {
+            LLama::with_progress(path, &opts, |fraction| {
+                *progress.lock().unwrap() = fraction;
+            })
+        })
+        .await
+        .map_err(|_| LlamaError::ModelLoad {
+            path: self.path.clone(),
+        })
+        .and_then(|loaded| loaded);
+        let loaded = match loaded {
+            Ok(loaded) => Arc::new(loaded),
+            Err(err) => {
+                *self.state.lock().unwrap() = ModelState::Unloaded;
+                slog::error!(LOGGER, "failed to load model {}: {}", self.path, err);
+                return Err(err);
+            }
+        };
+        slog::info!(
+            LOGGER,
+            "loaded model {} in {:.1}s",
+            self.path,
+            started.elapsed().as_secs_f32()
+        );
+
+        *llama = Some(loaded.clone());
+        *self.state.lock().unwrap() = ModelState::Loaded;
+        Ok(self.guard(loaded))
+    }
+
+    /// Drops the slot's reference to the model, which is freed once requests
+    /// still using it finish. Returns whether it was loaded.
+    pub async fn unload(&self, reason: &str) -> bool {
+        let mut llama = self.llama.lock().await;
+        let Some(unloaded) = llama.take() else {
+            return false;
+        };
+        self.retired.lock().unwrap().push(Arc::downgrade(&unloaded));
+        *self.state.lock().unwrap() = ModelState::Unloaded;
+        slog::info!(LOGGER, "unloaded model {} ({})", self.path, reason);
+        true
+    }
+
+    /// Unloads the model when no request has used it for `timeout` and none is
+    /// using it now.
+    pub async fn unload_if_idle(&self, timeout: Duration) -> bool {
+        let Ok(mut llama) = self.llama.try_lock() else {
+            // loading right now
+            return false;
+        };
+        let Some(loaded) = &*llama else {
+            return false;
+        };
+        if Arc::strong_count(loaded) > 1 || self.last_used().elapsed() < timeout {
+            return false;
+        }
+
+        llama.take();
+        *self.state.lock().unwrap() = ModelState::Unloaded;
+        slog::info!(
+            LOGGER,
+            "unloaded model {} after {}s idle",
+            self.path,
+            timeout.as_secs()
+        );
+        true
+    }
+}
+
+/// A loaded model held by a request. The model counts as used until the guard
+/// is dropped, so the idle timeout runs from the end of the request.
+pub struct ModelGuard {
+    llama: Arc<LLama>,
+    last_used: Arc<Mutex<Instant>>,
+}
+
+impl Deref for ModelGuard {
+    type Target = LLama;
+
+    fn deref(&self) -> &LLama {
+        &self.llama
+    }
+}
+
+impl Drop for ModelGuard {
+    fn drop(&mut self) {
+        *self.last_used.lock().unwrap() = Instant::now();
+    }
+}
+
+/// Holds the model requests run on and swaps it for another without a restart.
+///
+/// Requests take an `Arc` of the current model, so a swap only affects requests
+/// started after it. The previous model is freed once the last request still
+/// using it finishes.
+pub struct ModelManager {
+    current: RwLock<Arc<ModelSlot>>,
+}
+
+impl ModelManager {
+    /// The model is loaded by the first request.
+    pub fn new(path: String, opts: ModelOptions) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(ModelSlot::new(path, opts))),
+        }
+    }
+
+    /// The slot of the model new requests should use.
+    pub fn current(&self) -> Arc<ModelSlot> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// The model new requests should use, loading it if it was unloaded.
+    pub async fn get(&self) -> Result<ModelGuard, LlamaError> {
+        self.current().get().await
+    }
+
+    /// Loads `model`, then makes it the current model. The current model keeps
+    /// serving requests while the new one loads, and stays current if loading
+    /// fails.
+    pub async fn swap(&self, model: String, opts: ModelOptions) -> Result<ModelGuard, LlamaError> {
+        let slot = Arc::new(ModelSlot::new(model, opts));
+        let llama = slot.get().await?;
+
+        let previous = std::mem::replace(&mut *self.current.write().unwrap(), slot);
+        slog::info!(
+            LOGGER,
+            "switched from model {} to {}",
+            previous.path(),
+            llama.model_path()
+        );
+
+        Ok(llama)
+    }
+}
@@ -0,0 +1,136 @@
+use std::{fmt, path::Path};
+
+use serde::Serialize;
+
+use super::gguf::{GgufError, GgufFile};
+use crate::LOGGER;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Memory a model takes once loaded, estimated from its GGUF header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MemoryEstimate {
+    pub weights: u64,
+    /// Keys and values of every layer for the whole context, stored as F16.
+    pub kv_cache: u64,
+}
+
+impl MemoryEstimate {
+    /// `context_size` of 0 or less means the context the model was trained on,
+    /// as llama.cpp does.
+    pub fn for_model<P: AsRef<Path>>(path: P, context_size: i32) -> Result<Self, GgufError> {
+        let gguf = GgufFile::open(path)?;
+
+        let weights = gguf
+            .tensors
+            .iter()
+            .map(|tensor| tensor.n_bytes())
+            .sum::<Option<u64>>()
+            .unwrap_or(gguf.file_size - gguf.data_offset);
+
+        let n_ctx = if context_size > 0 {
+            context_size as u64
+        } else {
+            gguf.get_arch_u64("context_length").unwrap_or(0)
+        };
+        let n_layer = gguf.get_arch_u64("block_count").unwrap_or(0);
+        let n_embd = gguf.get_arch_u64("embedding_length").unwrap_or(0);
+        let n_head = gguf
+            .get_arch_u64("attention.head_count")
+            .unwrap_or(1)
+            .max(1);
+        let n_head_kv = gguf
+            .get_arch_u64("attention.head_count_kv")
+            .unwrap_or(n_head);
+        let n_embd_kv = n_embd / n_head * n_head_kv;
+        let kv_cache = kv_cache_size(n_layer, n_ctx, n_embd_kv);
+
+        Ok(Self { weights, kv_cache })
+    }
+
+    /// Like `for_model`, but when the GGUF header can't be read it warns and
+    /// counts the file's size as weights, leaving llama.cpp to report what's
+    /// wrong with the file.
+    pub fn for_model_or_file_size<P: AsRef<Path>>(path: P, context_size: i32) -> Self {
+        let path = path.as_ref();
+        Self::for_model(path, context_size).unwrap_or_else(|err| {
+            slog::warn!(
+                LOGGER,
+                "can't estimate the memory {} needs: {}",
+                path.display(),
+                err
+            );
+            Self {
+                weights: std::fs::metadata(path).map_or(0, |meta| meta.len()),
+                kv_cache: 0,
+            }
+        })
+    }
+
+    pub fn total(&self) -> u64 {
+        self.weights + self.kv_cache
+    }
+}
+
+impl fmt::Display for MemoryEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} MiB ({} MiB of weights and {} MiB of KV cache)",
+            self.total() / MIB,
+            self.weights / MIB,
+            self.kv_cache / MIB
+        )
+    }
+}
+
+// Keys and values of `n_layer` layers for `n_ctx` tokens. load_model doesn't
+// pass `ModelOptions::f16_memory` on, so llama.cpp always keeps them as F16.
+fn kv_cache_size(n_layer: u64, n_ctx: u64, n_embd_kv: u64) -> u64 {
+    2 * n_layer * n_ctx * n_embd_kv * 2
+}
+
+/// `MemAvailable` of /proc/meminfo, `None` where it can't be read.
+pub fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|value| value.trim().strip_suffix("kB"))
+        .and_then(|kb| kb.trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[test]
+    fn kv_cache_is_f16() {
+        // 32 layers of 4096 values for 2048 tokens, keys and values
+        assert_eq!(kv_cache_size(32, 2048, 4096), 1024 * MIB);
+    }
+
+    #[test]
+    fn estimates_from_the_header() {
+        let estimate = MemoryEstimate::for_model(fixture("valid.gguf"), 512).unwrap();
+        assert_eq!(estimate.weights, 48);
+        assert_eq!(estimate.total(), estimate.weights + estimate.kv_cache);
+    }
+
+    #[test]
+    fn falls_back_to_the_file_size() {
+        let path = fixture("bad-magic.gguf");
+        assert!(MemoryEstimate::for_model(&path, 512).is_err());
+        let estimate = MemoryEstimate::for_model_or_file_size(&path, 512);
+        assert_eq!(estimate.weights, std::fs::metadata(&path).unwrap().len());
+        assert_eq!(estimate.kv_cache, 0);
+
+        let missing = MemoryEstimate::for_model_or_file_size(fixture("missing.gguf"), 512);
+        assert_eq!(missing.total(), 0);
+    }
+}
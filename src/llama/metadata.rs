@@ -0,0 +1,99 @@
+use std::{collections::BTreeMap, fmt};
+
+use serde::Serialize;
+
+use super::gguf::GgufFile;
+
+// Values longer than this are cut when printed as text, tokenizer arrays are huge.
+const MAX_TEXT_VALUE_LEN: usize = 60;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelMetadata {
+    pub architecture: String,
+    pub description: String,
+    pub n_params: u64,
+    pub n_vocab: i32,
+    pub n_ctx_train: i32,
+    pub n_embd: i32,
+    pub quantization: String,
+    pub file_size: u64,
+    pub kv: BTreeMap<String, String>,
+}
+
+/// Name of a `general.file_type` value as used by llama.cpp's quantize tool.
+pub fn file_type_name(file_type: u32) -> &'static str {
+    match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        4 => "Q4_1_SOME_F16",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        _ => "unknown",
+    }
+}
+
+impl ModelMetadata {
+    /// Builds the metadata from a GGUF header alone, without loading the model.
+    pub fn from_gguf(gguf: &GgufFile) -> Self {
+        let architecture = gguf.architecture().unwrap_or_default().to_string();
+        let quantization = gguf
+            .get_u64("general.file_type")
+            .map(|t| file_type_name(t as u32))
+            .unwrap_or("unknown")
+            .to_string();
+
+        Self {
+            description: gguf
+                .get_str("general.name")
+                .unwrap_or(&architecture)
+                .to_string(),
+            architecture,
+            n_params: gguf.n_params(),
+            n_vocab: gguf.n_vocab().unwrap_or(0) as i32,
+            n_ctx_train: gguf.get_arch_u64("context_length").unwrap_or(0) as i32,
+            n_embd: gguf.get_arch_u64("embedding_length").unwrap_or(0) as i32,
+            quantization,
+            file_size: gguf.file_size,
+            kv: gguf
+                .metadata
+                .iter()
+                .map(|(k, v)| (k.clone(), v.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for ModelMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "architecture:  {}", self.architecture)?;
+        writeln!(f, "description:   {}", self.description)?;
+        writeln!(f, "parameters:    {}", self.n_params)?;
+        writeln!(f, "vocab size:    {}", self.n_vocab)?;
+        writeln!(f, "n_ctx_train:   {}", self.n_ctx_train)?;
+        writeln!(f, "n_embd:        {}", self.n_embd)?;
+        writeln!(f, "quantization:  {}", self.quantization)?;
+        writeln!(f, "file size:     {}", self.file_size)?;
+        writeln!(f, "metadata:")?;
+        for (key, value) in &self.kv {
+            if value.chars().count() > MAX_TEXT_VALUE_LEN {
+                let value: String = value.chars().take(MAX_TEXT_VALUE_LEN).collect();
+                writeln!(f, "  {} = {}...", key, value)?;
+            } else {
+                writeln!(f, "  {} = {}", key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,56 +1,179 @@
 use std::{
-    collections::HashMap,
-    ffi::{c_char, c_void, CStr, CString},
-    mem::size_of,
-    sync::Mutex,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    fs::File,
+    io::Read,
+    panic::AssertUnwindSafe,
+    sync::{Mutex, Once, OnceLock},
+    time::{Duration, Instant},
 };
 
-use crate::{config::config_model_or_default, Result};
-use async_once::AsyncOnce;
+use crate::{
+    config::{
+        config_draft_model, config_m_map, config_model_or_default, config_models,
+        config_models_memory_limit,
+    },
+    LOGGER,
+};
+use beam::{Beam, BeamSearch};
+use error::LlamaError;
+use gguf::GgufFile;
+use grammar::GrammarError;
 use lazy_static::lazy_static;
-use options::{ModelOptions, PredictOptions};
-
+use logprobs::{trim_logprobs, LogprobsCallback, TokenLogprob, TokenLogprobs};
+use manager::ModelManager;
+use memory::{available_memory, MemoryEstimate};
+use metadata::{file_type_name, ModelMetadata};
+use options::{LoraAdapter, ModelOptions, PredictOptions};
+use parking_lot::ReentrantMutex;
+use rand::Rng;
+use registry::ModelRegistry;
+use result::{FinishReason, PredictResult, SpeculativeStats};
+use sampler::{repeat_last_n, Candidate, Candidates, RepetitionPenalty, SamplerChain, Selection};
+use sha2::{Digest, Sha256};
+use stop::{StopConditions, StopMatcher, TokenStream};
+
+pub mod beam;
+pub mod error;
+pub mod gguf;
+pub mod grammar;
+pub mod json_schema;
+pub mod logprobs;
+pub mod manager;
+pub mod memory;
+pub mod metadata;
 pub mod options;
+pub mod registry;
+pub mod result;
+pub mod sampler;
+pub mod stop;
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
 pub type Callback = Box<dyn Fn(String) -> bool + Send + 'static>;
 
+// llama.cpp's batch size when `ModelOptions::n_batch` isn't set.
+const DEFAULT_N_BATCH: usize = 512;
+
 lazy_static! {
     static ref CALLBACKS: Mutex<HashMap<usize, Callback>> = Mutex::new(HashMap::new());
-    pub static ref LOCAL_LLAMA: AsyncOnce<LLama> = AsyncOnce::new(async { new_llama().await });
+    static ref LOGPROBS: Mutex<HashMap<usize, LogprobsSink>> = Mutex::new(HashMap::new());
+    static ref SAMPLERS: Mutex<HashMap<usize, SamplerChain>> = Mutex::new(HashMap::new());
+    static ref STREAMS: Mutex<HashMap<usize, TokenStream>> = Mutex::new(HashMap::new());
+    pub static ref MODEL_MANAGER: ModelManager =
+        ModelManager::new(config_model_or_default(), default_model_options());
+    pub static ref MODEL_REGISTRY: ModelRegistry =
+        ModelRegistry::new(config_models(), config_models_memory_limit());
 }
 
-async fn new_llama() -> LLama {
-    let model_options = ModelOptions::default();
-    LLama::new(config_model_or_default(), &model_options).unwrap()
+thread_local! {
+    // llama.cpp logs lines in pieces, this holds the start of the current one.
+    // Per thread, so that contexts logging at once don't interleave.
+    static LOG_LINE: RefCell<LogLine> = RefCell::new(LogLine::default());
+}
+
+/// The options the configured model is loaded with.
+pub fn default_model_options() -> ModelOptions {
+    let mut model_options = ModelOptions::default();
+    if let Some(draft_model) = config_draft_model() {
+        model_options.set_draft_model(draft_model);
+    }
+    if let Some(m_map) = config_m_map() {
+        model_options.set_m_map(m_map);
+    }
+    model_options
+}
+
+/// Unloads the models no request has used for `timeout` in the background, the
+/// next request that needs one loads it again.
+pub fn spawn_idle_unloader(timeout: Duration) -> tokio::task::JoinHandle<()> {
+    let period = (timeout / 4).clamp(Duration::from_secs(1), Duration::from_secs(60));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            MODEL_MANAGER.current().unload_if_idle(timeout).await;
+            MODEL_REGISTRY.unload_idle(timeout).await;
+        }
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenMatch {
+    Exact,
+    Prefix,
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct LLama {
     state: *mut c_void,
+    // Held around every llama.cpp call on `state`. Reentrant because methods
+    // call each other, and the token callbacks run inside llama_predict.
+    context: ReentrantMutex<()>,
     embeddings: bool,
-    context_size: i32,
+    // Tokens the context holds, as llama.cpp allocated it: a `context_size` of
+    // 0 or less gives the context the model was trained on.
+    context_size: usize,
+    // Most tokens one llama_decode call takes.
+    n_batch: usize,
+    model_path: String,
+    model_hash: OnceLock<String>,
+    // Tokens currently held in the context, reused as a prompt prefix by the next predict.
+    kv_tokens: Mutex<Vec<i32>>,
+    // Proposes tokens for speculative decoding, see `ModelOptions::draft_model`.
+    draft: Option<Box<LLama>>,
+    m_map: bool,
+    lora_base: String,
+    // Whether the weights are quantized, which makes merging adapters lossy.
+    quantized: bool,
+    // LoRA adapters merged into the weights, with the scale they were merged with.
+    lora_adapters: Mutex<Vec<LoraAdapter>>,
 }
 
+// SAFETY: llama.cpp contexts aren't thread-safe, so every method that passes
+// `state` to llama.cpp holds `context` while doing so, and the per-context
+// callbacks and streams are only registered under it.
+unsafe impl Send for LLama {}
+unsafe impl Sync for LLama {}
+
 impl LLama {
-    pub fn new(model: String, opts: &ModelOptions) -> Result<Self> {
-        let model_path = CString::new(model).unwrap();
-        let main_gpu_cstr = CString::new(opts.main_gpu.clone()).unwrap();
+    pub fn new(model: String, opts: &ModelOptions) -> Result<Self, LlamaError> {
+        Self::with_progress(model, opts, |_| {})
+    }
+
+    /// Like `new`, calling `progress` with the fraction of the model loaded so
+    /// far while llama.cpp loads it.
+    pub fn with_progress(
+        model: String,
+        opts: &ModelOptions,
+        mut progress: impl FnMut(f32),
+    ) -> Result<Self, LlamaError> {
+        static LOG_SET: Once = Once::new();
+        LOG_SET.call_once(|| unsafe { llama_binding_log_set() });
+        check_memory(&model, opts)?;
+
+        let c_model_path = CString::new(model.clone()).map_err(|_| LlamaError::ModelLoad {
+            path: model.clone(),
+        })?;
+        let main_gpu_cstr = c_string(&opts.main_gpu, "main_gpu")?;
         let main_gpu = main_gpu_cstr.as_ptr();
-        let tensor_split_cstr = CString::new(opts.tensor_split.clone()).unwrap();
+        let tensor_split_cstr = c_string(&opts.tensor_split, "tensor_split")?;
         let tensor_split = tensor_split_cstr.as_ptr();
+        // adapters write to the weights, which mmap maps read-only
+        let m_map = opts.m_map && opts.lora_adapters.is_empty();
+        let mut progress: &mut dyn FnMut(f32) = &mut progress;
 
         unsafe {
             let result = load_model(
-                model_path.as_ptr(),
+                c_model_path.as_ptr(),
                 opts.context_size,
                 opts.seed,
                 opts.f16_memory,
                 opts.m_lock,
                 opts.embeddings,
-                opts.m_map,
+                m_map,
                 opts.low_vram,
                 opts.vocab_only,
                 opts.n_gpu_layers,
@@ -58,58 +181,566 @@ impl LLama {
                 main_gpu,
                 tensor_split,
                 opts.numa,
+                &mut progress as *mut &mut dyn FnMut(f32) as *mut c_void,
             );
 
             if result.is_null() {
-                Err("Failed to load model".into())
+                Err(LlamaError::ModelLoad { path: model })
             } else {
-                Ok(Self {
+                let mut llama = Self {
                     state: result,
+                    context: ReentrantMutex::new(()),
                     embeddings: opts.embeddings,
-                    context_size: opts.context_size,
-                })
+                    context_size: llama_binding_n_ctx(result).max(0) as usize,
+                    n_batch: if opts.n_batch > 0 {
+                        opts.n_batch as usize
+                    } else {
+                        DEFAULT_N_BATCH
+                    },
+                    quantized: is_quantized(&model),
+                    model_path: model,
+                    model_hash: OnceLock::new(),
+                    kv_tokens: Mutex::new(Vec::new()),
+                    draft: None,
+                    m_map,
+                    lora_base: opts.lora_base.clone(),
+                    lora_adapters: Mutex::new(Vec::new()),
+                };
+
+                let n_ctx_train = llama_binding_n_ctx_train(llama.state);
+                if opts.context_size > n_ctx_train {
+                    slog::warn!(
+                        LOGGER,
+                        "context size {} exceeds the {} tokens {} was trained on",
+                        opts.context_size,
+                        n_ctx_train,
+                        llama.model_path
+                    );
+                }
+
+                if let Some(draft_model) = &opts.draft_model {
+                    let draft = LLama::new(
+                        draft_model.clone(),
+                        &ModelOptions {
+                            draft_model: None,
+                            lora_adapters: vec![],
+                            ..opts.clone()
+                        },
+                    )?;
+                    if draft.n_vocab() != llama.n_vocab() {
+                        return Err(LlamaError::InvalidOptions(format!(
+                            "draft model {} has {} tokens, {} has {}",
+                            draft.model_path,
+                            draft.n_vocab(),
+                            llama.model_path,
+                            llama.n_vocab()
+                        )));
+                    }
+                    llama.draft = Some(Box::new(draft));
+                }
+                llama.set_lora_adapters(&opts.lora_adapters)?;
+
+                Ok(llama)
             }
         }
     }
 
     pub fn free_model(&self) {
+        let _context = self.context.lock();
         unsafe {
             llama_binding_free_model(self.state);
         }
     }
 
-    pub fn load_state(&self, state: String) -> Result<()> {
-        let d = CString::new(state).unwrap().into_raw();
+    /// LoRA adapters currently merged into the weights.
+    pub fn lora_adapters(&self) -> Vec<LoraAdapter> {
+        self.lora_adapters.lock().unwrap().clone()
+    }
+
+    /// Merges the adapter at `path` into the weights with `scale`, or changes
+    /// its scale if it's already attached. The base weights aren't reloaded,
+    /// so scales can only change when the model isn't quantized and has no
+    /// `lora_base`.
+    pub fn attach_lora(&self, path: &str, scale: f32) -> Result<(), LlamaError> {
+        let _context = self.context.lock();
+        let mut adapters = self.lora_adapters.lock().unwrap();
+        let attached = adapters.iter().position(|adapter| adapter.path == path);
+        let current = attached.map_or(0.0, |i| adapters[i].scale);
+        if attached.is_some() && scale != current {
+            self.check_lora_reversible(path)?;
+        }
+        if attached.is_none() && !self.lora_base.is_empty() && !adapters.is_empty() {
+            return Err(LlamaError::InvalidOptions(format!(
+                "can't attach LoRA adapter {}: with lora_base, llama.cpp replaces the weights \
+                 with the base's plus the adapter's, dropping the adapters already attached",
+                path
+            )));
+        }
+        self.apply_lora(path, scale - current)?;
+        match attached {
+            Some(i) => adapters[i].scale = scale,
+            None => adapters.push(LoraAdapter::new(path, scale)),
+        }
+
+        Ok(())
+    }
+
+    pub fn rescale_lora(&self, path: &str, scale: f32) -> Result<(), LlamaError> {
+        if !self
+            .lora_adapters()
+            .iter()
+            .any(|adapter| adapter.path == path)
+        {
+            return Err(LlamaError::InvalidOptions(format!(
+                "LoRA adapter {} is not attached",
+                path
+            )));
+        }
+        self.attach_lora(path, scale)
+    }
+
+    /// Takes the adapter's deltas back out of the weights.
+    pub fn detach_lora(&self, path: &str) -> Result<(), LlamaError> {
+        let _context = self.context.lock();
+        let mut adapters = self.lora_adapters.lock().unwrap();
+        let Some(i) = adapters.iter().position(|adapter| adapter.path == path) else {
+            return Err(LlamaError::InvalidOptions(format!(
+                "LoRA adapter {} is not attached",
+                path
+            )));
+        };
+        self.check_lora_reversible(path)?;
+        self.apply_lora(path, -adapters[i].scale)?;
+        adapters.remove(i);
+
+        Ok(())
+    }
+
+    /// Attaches, rescales and detaches adapters until exactly `adapters` are
+    /// merged.
+    pub fn set_lora_adapters(&self, adapters: &[LoraAdapter]) -> Result<(), LlamaError> {
+        let _context = self.context.lock();
+        for current in self.lora_adapters() {
+            if !adapters.iter().any(|adapter| adapter.path == current.path) {
+                self.detach_lora(&current.path)?;
+            }
+        }
+        for adapter in adapters {
+            if !self.lora_adapters().contains(adapter) {
+                self.attach_lora(&adapter.path, adapter.scale)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Merging a negative scale only restores the weights when the deltas were
+    // added to unquantized weights of the model itself: with `lora_base` the
+    // weights were replaced, and quantized weights are rounded after each merge.
+    fn check_lora_reversible(&self, path: &str) -> Result<(), LlamaError> {
+        let reason = if !self.lora_base.is_empty() {
+            "it was merged against lora_base"
+        } else if self.quantized {
+            "the model is quantized"
+        } else {
+            return Ok(());
+        };
+        Err(LlamaError::InvalidOptions(format!(
+            "can't rescale or detach LoRA adapter {} because {}, reload the model instead",
+            path, reason
+        )))
+    }
+
+    // llama.cpp merges adapters into the weights, so a delta of the scale is
+    // applied: a negative scale takes an adapter back out.
+    fn apply_lora(&self, path: &str, scale: f32) -> Result<(), LlamaError> {
+        let _context = self.context.lock();
+        if scale == 0.0 {
+            return Ok(());
+        }
+        if self.m_map {
+            return Err(LlamaError::InvalidOptions(format!(
+                "can't apply LoRA adapter {} to the memory-mapped model {}, load it with m_map = false",
+                path, self.model_path
+            )));
+        }
+
+        // no decode may run while the weights change, and the context was
+        // computed with the previous weights
+        let mut kv_tokens = self.kv_tokens.lock().unwrap();
+        kv_tokens.clear();
+
+        let path_cstr = c_string(path, "LoRA path")?;
+        let lora_base_cstr = c_string(&self.lora_base, "lora_base")?;
+        let n_threads = std::thread::available_parallelism().map_or(4, |n| n.get()) as i32;
+        let ret = unsafe {
+            llama_binding_apply_lora(
+                self.state,
+                path_cstr.as_ptr(),
+                scale,
+                lora_base_cstr.as_ptr(),
+                n_threads,
+            )
+        };
+        if ret != 0 {
+            return Err(LlamaError::Lora {
+                path: path.to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn model_path(&self) -> &str {
+        &self.model_path
+    }
+
+    /// Hex encoded SHA-256 of the model file, computed on first use.
+    pub fn model_hash(&self) -> Result<String, LlamaError> {
+        if let Some(hash) = self.model_hash.get() {
+            return Ok(hash.clone());
+        }
+
+        let mut file = File::open(&self.model_path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 1 << 20];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        let hash: String = hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        Ok(self.model_hash.get_or_init(|| hash).clone())
+    }
+
+    /// Copies the full context state (KV cache, logits and RNG) into memory.
+    pub fn state_bytes(&self) -> Vec<u8> {
+        let _context = self.context.lock();
+        unsafe {
+            let size = llama_binding_state_size(self.state);
+            let mut buf = vec![0u8; size];
+            let written = llama_binding_copy_state(self.state, buf.as_mut_ptr());
+            buf.truncate(written);
+            buf
+        }
+    }
+
+    /// Restores a context state captured with `state_bytes`. `kv_tokens` are the tokens
+    /// that state holds, so the next predict can skip re-evaluating them.
+    pub fn set_state_bytes(&self, state: &[u8], kv_tokens: &[i32]) -> Result<(), LlamaError> {
+        let _context = self.context.lock();
+        let mut current = self.kv_tokens.lock().unwrap();
+        current.clear();
+
+        unsafe {
+            // `state_bytes` only writes the used part of the KV cache, so the
+            // state can be smaller than the most the context holds
+            let size = llama_binding_state_size(self.state);
+            if state.len() > size {
+                return Err(LlamaError::StateIo(
+                    "state is larger than the loaded context holds".to_string(),
+                ));
+            }
+            // llama.cpp reads without a length, a truncated state must not make
+            // it read past the buffer
+            let mut buf = state.to_vec();
+            buf.resize(size, 0);
+            let read = llama_binding_set_state(self.state, buf.as_mut_ptr());
+            if read != state.len() {
+                return Err(LlamaError::StateIo(format!(
+                    "llama.cpp read {} of {} state bytes",
+                    read,
+                    state.len()
+                )));
+            }
+        }
+
+        current.extend_from_slice(kv_tokens);
+        Ok(())
+    }
+
+    /// Tokens currently held in the context.
+    pub fn kv_tokens(&self) -> Vec<i32> {
+        self.kv_tokens.lock().unwrap().clone()
+    }
+
+    /// Tokenizes `text` as is. Note that `predict` prepends a space to the prompt
+    /// before tokenizing it.
+    pub fn tokenize(&self, text: &str, add_bos: bool) -> Result<Vec<i32>, LlamaError> {
+        let _context = self.context.lock();
+        let mut tokens = vec![0i32; text.len() + add_bos as usize + 1];
+
+        unsafe {
+            let mut n = llama_binding_tokenize(
+                self.state,
+                text.as_ptr() as *const c_char,
+                text.len() as i32,
+                tokens.as_mut_ptr(),
+                tokens.len() as i32,
+                add_bos,
+            );
+            if n < 0 {
+                tokens.resize(-n as usize, 0);
+                n = llama_binding_tokenize(
+                    self.state,
+                    text.as_ptr() as *const c_char,
+                    text.len() as i32,
+                    tokens.as_mut_ptr(),
+                    tokens.len() as i32,
+                    add_bos,
+                );
+            }
+            if n < 0 {
+                return Err(LlamaError::Tokenize(format!(
+                    "llama.cpp rejected {} bytes of text",
+                    text.len()
+                )));
+            }
+            tokens.truncate(n as usize);
+        }
+
+        Ok(tokens)
+    }
+
+    /// Concatenates the pieces of `tokens`. Invalid UTF-8 from split multi-byte
+    /// characters is replaced.
+    pub fn detokenize(&self, tokens: &[i32]) -> Result<String, LlamaError> {
+        let mut bytes = Vec::new();
+        for &token in tokens {
+            bytes.extend(self.piece_bytes(token)?);
+        }
+
+        Ok(String::from_utf8_lossy(&bytes).to_string())
+    }
+
+    pub fn token_to_piece(&self, token: i32) -> Result<String, LlamaError> {
+        Ok(String::from_utf8_lossy(&self.piece_bytes(token)?).to_string())
+    }
+
+    fn piece_bytes(&self, token: i32) -> Result<Vec<u8>, LlamaError> {
+        let _context = self.context.lock();
+        if token < 0 || token >= self.n_vocab() {
+            return Err(LlamaError::Tokenize(format!(
+                "token {} is out of the vocabulary",
+                token
+            )));
+        }
+
+        Ok(token_piece(self.state, token))
+    }
+
+    /// Ids of the tokens whose text, ignoring leading whitespace, equals `text`
+    /// or starts with it.
+    pub fn tokens_matching(&self, text: &str, matching: TokenMatch) -> Vec<i32> {
+        let _context = self.context.lock();
+        (0..self.n_vocab())
+            .filter(|&token| {
+                let piece = token_piece(self.state, token);
+                let piece = String::from_utf8_lossy(&piece);
+                let piece = piece.trim_start();
+                match matching {
+                    TokenMatch::Exact => piece == text,
+                    TokenMatch::Prefix => !piece.is_empty() && piece.starts_with(text),
+                }
+            })
+            .collect()
+    }
+
+    /// Ids of the tokens whose text contains any of `texts`.
+    pub fn tokens_containing(&self, texts: &[String]) -> HashSet<i32> {
+        let _context = self.context.lock();
+        (0..self.n_vocab())
+            .filter(|&token| {
+                let piece = token_piece(self.state, token);
+                let piece = String::from_utf8_lossy(&piece);
+                texts.iter().any(|text| piece.contains(text.as_str()))
+            })
+            .collect()
+    }
+
+    /// The Rust sampler chain matching `opts`, see `SamplerChain::from_options`.
+    pub fn sampler_chain(&self, opts: &PredictOptions) -> SamplerChain {
+        let dry_breakers = opts
+            .dry
+            .as_ref()
+            .map(|dry| self.tokens_containing(&dry.sequence_breakers))
+            .unwrap_or_default();
+
+        SamplerChain::from_options(opts, self.token_nl(), dry_breakers, self.context_size)
+    }
+
+    /// Biases every token matching `text`, `f32::NEG_INFINITY` bans them.
+    /// Returns how many tokens were biased.
+    pub fn bias_matching_tokens(
+        &self,
+        opts: &mut PredictOptions,
+        text: &str,
+        matching: TokenMatch,
+        bias: f32,
+    ) -> usize {
+        let tokens = self.tokens_matching(text, matching);
+        for &token in &tokens {
+            opts.set_logit_bias(token, bias);
+        }
+
+        tokens.len()
+    }
+
+    // The logit bias of `opts`, with EOS banned when `opts.ignore_eos` is set.
+    // Every generation path takes its bias from here, so they agree on EOS.
+    fn logit_bias_arrays(&self, opts: &PredictOptions) -> Result<(Vec<i32>, Vec<f32>), LlamaError> {
+        let n_vocab = self.n_vocab();
+        if let Some(token) = opts.logit_bias.keys().find(|&&t| t < 0 || t >= n_vocab) {
+            return Err(LlamaError::InvalidOptions(format!(
+                "logit bias token {} is out of the vocabulary of {} tokens",
+                token, n_vocab
+            )));
+        }
+
+        let mut logit_bias = opts.logit_bias.clone();
+        if opts.ignore_eos {
+            logit_bias.insert(self.token_eos(), f32::NEG_INFINITY);
+        }
+        Ok(logit_bias.into_iter().unzip())
+    }
+
+    pub fn n_vocab(&self) -> i32 {
+        let _context = self.context.lock();
+        unsafe { llama_binding_n_vocab(self.state) }
+    }
+
+    pub fn n_embd(&self) -> i32 {
+        let _context = self.context.lock();
+        unsafe { llama_binding_n_embd(self.state) }
+    }
+
+    pub fn token_bos(&self) -> i32 {
+        let _context = self.context.lock();
+        unsafe { llama_binding_token_bos(self.state) }
+    }
+
+    pub fn token_eos(&self) -> i32 {
+        let _context = self.context.lock();
+        unsafe { llama_binding_token_eos(self.state) }
+    }
+
+    pub fn token_nl(&self) -> i32 {
+        let _context = self.context.lock();
+        unsafe { llama_binding_token_nl(self.state) }
+    }
+
+    pub fn token_prefix(&self) -> i32 {
+        let _context = self.context.lock();
+        unsafe { llama_binding_token_prefix(self.state) }
+    }
+
+    pub fn token_suffix(&self) -> i32 {
+        let _context = self.context.lock();
+        unsafe { llama_binding_token_suffix(self.state) }
+    }
+
+    pub fn token_middle(&self) -> i32 {
+        let _context = self.context.lock();
+        unsafe { llama_binding_token_middle(self.state) }
+    }
+
+    pub fn token_eot(&self) -> i32 {
+        let _context = self.context.lock();
+        unsafe { llama_binding_token_eot(self.state) }
+    }
+
+    pub fn n_ctx_train(&self) -> i32 {
+        let _context = self.context.lock();
+        unsafe { llama_binding_n_ctx_train(self.state) }
+    }
+
+    pub fn metadata(&self) -> Result<ModelMetadata, LlamaError> {
+        let _context = self.context.lock();
+        let mut kv = BTreeMap::new();
+
+        unsafe {
+            for i in 0..llama_binding_meta_count(self.state) {
+                let key = read_c_string(|buf, len| llama_binding_meta_key(self.state, i, buf, len));
+                let val = read_c_string(|buf, len| llama_binding_meta_val(self.state, i, buf, len));
+                kv.insert(key, val);
+            }
+
+            let description =
+                read_c_string(|buf, len| llama_binding_model_desc(self.state, buf, len));
+            let quantization = match kv
+                .get("general.file_type")
+                .and_then(|t| t.parse::<u32>().ok())
+            {
+                Some(file_type) => file_type_name(file_type).to_string(),
+                None => description.split(' ').skip(2).collect::<Vec<_>>().join(" "),
+            };
+
+            Ok(ModelMetadata {
+                architecture: kv.get("general.architecture").cloned().unwrap_or_default(),
+                description,
+                n_params: llama_binding_model_n_params(self.state),
+                n_vocab: self.n_vocab(),
+                n_ctx_train: self.n_ctx_train(),
+                n_embd: self.n_embd(),
+                quantization,
+                file_size: std::fs::metadata(&self.model_path)?.len(),
+                kv,
+            })
+        }
+    }
+
+    pub fn load_state(&self, state: String) -> Result<(), LlamaError> {
+        let _context = self.context.lock();
+        let d = CString::new(state.clone())
+            .map_err(|_| LlamaError::StateIo(format!("invalid state path {:?}", state)))?
+            .into_raw();
         let w = CString::new("rb").unwrap().into_raw();
+        self.kv_tokens.lock().unwrap().clear();
 
         unsafe {
             let result = load_state(self.state, d, w);
 
             if result != 0 {
-                Err("Failed to load state".into())
+                Err(LlamaError::StateIo(format!(
+                    "failed to load state from {}",
+                    state
+                )))
             } else {
                 Ok(())
             }
         }
     }
 
-    pub fn save_state(&self, dst: String) -> Result<()> {
-        let d = CString::new(dst.clone()).unwrap().into_raw();
+    pub fn save_state(&self, dst: String) -> Result<(), LlamaError> {
+        let _context = self.context.lock();
+        let d = CString::new(dst.clone())
+            .map_err(|_| LlamaError::StateIo(format!("invalid state path {:?}", dst)))?
+            .into_raw();
         let w = CString::new("wb").unwrap().into_raw();
 
         unsafe {
             save_state(self.state, d, w);
         };
 
-        std::fs::metadata(dst).map_err(|_| "Failed to save state".to_string())?;
+        std::fs::metadata(&dst)
+            .map_err(|_| LlamaError::StateIo(format!("failed to save state to {}", dst)))?;
 
         Ok(())
     }
 
-    pub fn eval(&self, text: String, opts: &mut PredictOptions) -> Result<()> {
-        let c_str = CString::new(text.clone()).unwrap();
+    pub fn eval(&self, text: String, opts: &mut PredictOptions) -> Result<(), LlamaError> {
+        let _context = self.context.lock();
+        let c_str = prompt_c_string(&text)?;
         let input = c_str.as_ptr();
         let input2 = c_str.into_raw();
+        self.kv_tokens.lock().unwrap().clear();
 
         if opts.tokens == 0 {
             opts.tokens = 99999999;
@@ -121,7 +752,7 @@ impl LLama {
         let mut pass: *mut *const c_char = std::ptr::null_mut();
 
         for prompt in &opts.stop_prompts {
-            let c_string = CString::new(prompt.clone()).unwrap();
+            let c_string = prompt_c_string(prompt)?;
             reverse_prompt.push(c_string.as_ptr());
             c_strings.push(c_string);
         }
@@ -130,14 +761,15 @@ impl LLama {
             pass = reverse_prompt.as_mut_ptr();
         }
 
-        let logit_bias_cstr = CString::new(opts.logit_bias.clone()).unwrap();
-        let logit_bias = logit_bias_cstr.as_ptr();
-        let path_prompt_cache_cstr = CString::new(opts.path_prompt_cache.clone()).unwrap();
+        let (logit_bias_tokens, logit_bias_values) = self.logit_bias_arrays(opts)?;
+        let path_prompt_cache_cstr = c_string(&opts.path_prompt_cache, "path_prompt_cache")?;
         let path_prompt_cache = path_prompt_cache_cstr.as_ptr();
-        let main_gpu_cstr = CString::new(opts.main_gpu.clone()).unwrap();
+        let main_gpu_cstr = c_string(&opts.main_gpu, "main_gpu")?;
         let main_gpu = main_gpu_cstr.as_ptr();
-        let tensor_split_cstr = CString::new(opts.tensor_split.clone()).unwrap();
+        let tensor_split_cstr = c_string(&opts.tensor_split, "tensor_split")?;
         let tensor_split = tensor_split_cstr.as_ptr();
+        let grammar_cstr = c_string(opts.grammar.as_deref().unwrap_or_default(), "grammar")?;
+        let grammar = grammar_cstr.as_ptr();
 
         unsafe {
             let params = llama_allocate_params(
@@ -164,7 +796,9 @@ impl LLama {
                 opts.mirostat_eta,
                 opts.mirostat_tau,
                 opts.penalize_nl,
-                logit_bias,
+                logit_bias_tokens.as_ptr(),
+                logit_bias_values.as_ptr(),
+                logit_bias_tokens.len() as i32,
                 path_prompt_cache,
                 opts.prompt_cache_all,
                 opts.m_lock,
@@ -172,12 +806,14 @@ impl LLama {
                 main_gpu,
                 tensor_split,
                 opts.prompt_cache_ro,
+                grammar,
+                -1,
             );
 
             let ret = eval(params, self.state, input2);
 
             if ret != 0 {
-                return Err("Failed to predict".into());
+                return Err(LlamaError::Eval { code: ret });
             }
 
             llama_free_params(params);
@@ -190,31 +826,30 @@ impl LLama {
         &self,
         tokens: Vec<i32>,
         opts: &mut PredictOptions,
-    ) -> Result<Vec<f32>> {
+    ) -> Result<Vec<f32>, LlamaError> {
+        let _context = self.context.lock();
         if !self.embeddings {
-            return Err("model loaded without embeddings".into());
+            return Err(LlamaError::EmbeddingsDisabled);
         }
 
         if opts.tokens == 0 {
             opts.tokens = 99999999;
         }
 
-        let mut out = Vec::with_capacity(opts.tokens as usize);
-        let mut my_array: Vec<i32> = Vec::with_capacity(opts.tokens as usize * size_of::<i32>());
-
-        for (i, &v) in tokens.iter().enumerate() {
-            my_array[i] = v;
-        }
+        let mut out = vec![0.0; self.n_embd().max(0) as usize];
+        let mut tokens = tokens;
+        self.kv_tokens.lock().unwrap().clear();
 
-        let logit_bias_cstr = CString::new(opts.logit_bias.clone()).unwrap();
-        let logit_bias = logit_bias_cstr.as_ptr();
-        let path_prompt_cache_cstr = CString::new(opts.path_prompt_cache.clone()).unwrap();
+        let (logit_bias_tokens, logit_bias_values) = self.logit_bias_arrays(opts)?;
+        let path_prompt_cache_cstr = c_string(&opts.path_prompt_cache, "path_prompt_cache")?;
         let path_prompt_cache = path_prompt_cache_cstr.as_ptr();
-        let main_gpu_cstr = CString::new(opts.main_gpu.clone()).unwrap();
+        let main_gpu_cstr = c_string(&opts.main_gpu, "main_gpu")?;
         let main_gpu = main_gpu_cstr.as_ptr();
-        let tensor_split_cstr = CString::new(opts.tensor_split.clone()).unwrap();
+        let tensor_split_cstr = c_string(&opts.tensor_split, "tensor_split")?;
         let tensor_split = tensor_split_cstr.as_ptr();
-        let input = CString::new("").unwrap();
+        let grammar_cstr = c_string(opts.grammar.as_deref().unwrap_or_default(), "grammar")?;
+        let grammar = grammar_cstr.as_ptr();
+        let input = CString::default();
 
         unsafe {
             let params = llama_allocate_params(
@@ -241,7 +876,9 @@ impl LLama {
                 opts.mirostat_eta,
                 opts.mirostat_tau,
                 opts.penalize_nl,
-                logit_bias,
+                logit_bias_tokens.as_ptr(),
+                logit_bias_values.as_ptr(),
+                logit_bias_tokens.len() as i32,
                 path_prompt_cache,
                 opts.prompt_cache_all,
                 opts.m_lock,
@@ -249,31 +886,40 @@ impl LLama {
                 main_gpu,
                 tensor_split,
                 opts.prompt_cache_ro,
+                grammar,
+                -1,
             );
 
             let ret = get_token_embeddings(
                 params,
                 self.state,
-                my_array.as_mut_ptr(),
-                my_array.len() as i32,
+                tokens.as_mut_ptr(),
+                tokens.len() as i32,
                 out.as_mut_ptr(),
             );
+            llama_free_params(params);
 
             if ret != 0 {
-                return Err("Embedding inference failed".into());
+                return Err(LlamaError::Eval { code: ret });
             }
 
             Ok(out)
         }
     }
 
-    pub fn embeddings(&self, text: String, opts: &mut PredictOptions) -> Result<Vec<f32>> {
+    pub fn embeddings(
+        &self,
+        text: String,
+        opts: &mut PredictOptions,
+    ) -> Result<Vec<f32>, LlamaError> {
+        let _context = self.context.lock();
         if !self.embeddings {
-            return Err("model loaded without embeddings".into());
+            return Err(LlamaError::EmbeddingsDisabled);
         }
 
-        let c_str = CString::new(text.clone()).unwrap();
+        let c_str = prompt_c_string(&text)?;
         let input = c_str.as_ptr();
+        self.kv_tokens.lock().unwrap().clear();
 
         if opts.tokens == 0 {
             opts.tokens = 99999999;
@@ -285,7 +931,7 @@ impl LLama {
         let mut pass: *mut *const c_char = std::ptr::null_mut();
 
         for prompt in &opts.stop_prompts {
-            let c_string = CString::new(prompt.clone()).unwrap();
+            let c_string = prompt_c_string(prompt)?;
             reverse_prompt.push(c_string.as_ptr());
             c_strings.push(c_string);
         }
@@ -294,15 +940,16 @@ impl LLama {
             pass = reverse_prompt.as_mut_ptr();
         }
 
-        let mut out = Vec::with_capacity(opts.tokens as usize);
-        let logit_bias_cstr = CString::new(opts.logit_bias.clone()).unwrap();
-        let logit_bias = logit_bias_cstr.as_ptr();
-        let path_prompt_cache_cstr = CString::new(opts.path_prompt_cache.clone()).unwrap();
+        let mut out = vec![0.0; self.n_embd().max(0) as usize];
+        let (logit_bias_tokens, logit_bias_values) = self.logit_bias_arrays(opts)?;
+        let path_prompt_cache_cstr = c_string(&opts.path_prompt_cache, "path_prompt_cache")?;
         let path_prompt_cache = path_prompt_cache_cstr.as_ptr();
-        let main_gpu_cstr = CString::new(opts.main_gpu.clone()).unwrap();
+        let main_gpu_cstr = c_string(&opts.main_gpu, "main_gpu")?;
         let main_gpu = main_gpu_cstr.as_ptr();
-        let tensor_split_cstr = CString::new(opts.tensor_split.clone()).unwrap();
+        let tensor_split_cstr = c_string(&opts.tensor_split, "tensor_split")?;
         let tensor_split = tensor_split_cstr.as_ptr();
+        let grammar_cstr = c_string(opts.grammar.as_deref().unwrap_or_default(), "grammar")?;
+        let grammar = grammar_cstr.as_ptr();
 
         unsafe {
             let params = llama_allocate_params(
@@ -329,7 +976,9 @@ impl LLama {
                 opts.mirostat_eta,
                 opts.mirostat_tau,
                 opts.penalize_nl,
-                logit_bias,
+                logit_bias_tokens.as_ptr(),
+                logit_bias_values.as_ptr(),
+                logit_bias_tokens.len() as i32,
                 path_prompt_cache,
                 opts.prompt_cache_all,
                 opts.m_lock,
@@ -337,12 +986,15 @@ impl LLama {
                 main_gpu,
                 tensor_split,
                 opts.prompt_cache_ro,
+                grammar,
+                -1,
             );
 
             let ret = get_embeddings(params, self.state, out.as_mut_ptr());
+            llama_free_params(params);
 
             if ret != 0 {
-                return Err("Embedding inference failed".into());
+                return Err(LlamaError::Eval { code: ret });
             }
 
             Ok(out)
@@ -356,44 +1008,647 @@ impl LLama {
         set_callback(self.state, callback);
     }
 
-    pub fn predict(&self, text: String, opts: PredictOptions) -> Result<String> {
-        let c_str = CString::new(text.clone()).unwrap();
+    /// Predicts with `opts.response_schema` (if any) enforced and fails unless
+    /// the output parses as JSON, which is then in `json`.
+    pub fn predict_json(
+        &self,
+        text: String,
+        opts: PredictOptions,
+    ) -> Result<PredictResult, LlamaError> {
+        let mut res = self.predict(text, opts)?;
+        if res.json.is_none() {
+            match serde_json::from_str(res.text.trim()) {
+                Ok(json) => res.json = Some(json),
+                Err(_) if res.finish_reason == FinishReason::Cancelled => {
+                    return Err(LlamaError::Cancelled)
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(res)
+    }
+
+    pub fn predict(&self, text: String, opts: PredictOptions) -> Result<PredictResult, LlamaError> {
+        if opts.beam_width > 1 {
+            return self.beam_search(text, opts);
+        }
+        if let Some(draft) = &self.draft {
+            if opts.draft_tokens > 0 && is_plain_greedy(&opts) {
+                // llama_predict shifts the context once it's full, which the
+                // speculative loop doesn't, so it only takes completions that fit
+                let prompt = self.tokenize(&format!(" {}", text), true)?;
+                let n_ctx = self.context_size.min(draft.context_size);
+                if opts.tokens > 0 && prompt.len() + opts.tokens as usize <= n_ctx {
+                    return self.speculative_predict(draft, prompt, opts);
+                }
+            }
+        }
 
-        let input = c_str.as_ptr();
         let mut opts = opts;
+        let mut res = self.generate(text, &[], -1, &mut opts)?;
+        res.text = res.text.trim_start().to_string();
 
-        if opts.tokens == 0 {
-            opts.tokens = 99999999;
+        Ok(res)
+    }
+
+    /// Decodes `text` with beam search over `opts.beam_width` beams instead of
+    /// sampling. Of the sampling options only `tokens`, `logit_bias` and
+    /// `ignore_eos` apply. The token callback gets the best beam's text once the
+    /// search is over.
+    pub fn beam_search(
+        &self,
+        text: String,
+        opts: PredictOptions,
+    ) -> Result<PredictResult, LlamaError> {
+        let _context = self.context.lock();
+        let mut opts = opts;
+        opts.validate()?;
+        if let Some(adapters) = &opts.lora_adapters {
+            self.set_lora_adapters(adapters)?;
+        }
+        let _callback = CallbackGuard::register(self.state, opts.token_callback.take());
+
+        // llama_predict prepends the same space
+        let prompt = self.tokenize(&format!(" {}", text), true)?;
+        let n_ctx = self.context_size;
+        if prompt.len() + 4 > n_ctx {
+            return Err(LlamaError::ContextOverflow {
+                tokens: prompt.len(),
+                context_size: n_ctx,
+            });
         }
 
-        if let Some(callback) = opts.token_callback {
-            set_callback(self.state, Some(callback));
+        // every step decodes one token per beam in a single batch, and every beam
+        // takes its own cells in the KV cache
+        let width = opts.beam_width;
+        if width == 0 || width > self.n_batch {
+            return Err(LlamaError::InvalidOptions(format!(
+                "beam_width must be between 1 and the batch size of {}, got {}",
+                self.n_batch, width
+            )));
+        }
+        if prompt.len() + width > n_ctx {
+            return Err(LlamaError::InvalidOptions(format!(
+                "{} beams don't fit in the context after a prompt of {} tokens",
+                width,
+                prompt.len()
+            )));
+        }
+        let n_vocab = self.n_vocab() as usize;
+        let eos = self.token_eos();
+        let bias = self.bias_vector(&opts)?;
+        let mut max_tokens = (n_ctx - prompt.len()) / width;
+        if opts.tokens > 0 {
+            max_tokens = max_tokens.min(opts.tokens as usize);
         }
 
-        let reverse_count = opts.stop_prompts.len();
-        let mut c_strings: Vec<CString> = Vec::new();
-        let mut reverse_prompt = Vec::with_capacity(reverse_count);
-        let mut pass: *mut *const c_char = std::ptr::null_mut();
+        let mut kv_tokens = self.kv_tokens.lock().unwrap();
+        unsafe { llama_binding_set_n_threads(self.state, opts.threads) };
+
+        let prompt_start = Instant::now();
+        let mut logits = vec![0.0f32; n_vocab];
+        let cached = self.eval_prompt(&mut kv_tokens, &prompt, opts.batch, &mut logits)?;
+        let prompt_eval_ms = prompt_start.elapsed().as_secs_f64() * 1000.0;
+
+        // live beams alternate between two banks of sequences, each step copies
+        // its parents' sequences from one bank into the other
+        let gen_start = Instant::now();
+        let mut search = BeamSearch::new(width, opts.length_penalty, eos, 0);
+        let mut bank = 0;
+        for step in 0..max_tokens {
+            for row in logits.chunks_mut(n_vocab) {
+                row.iter_mut().zip(&bias).for_each(|(logit, b)| *logit += b);
+            }
+            search.step(&logits, n_vocab);
+            if search.is_done() || step + 1 == max_tokens {
+                break;
+            }
 
-        for prompt in &opts.stop_prompts {
-            let c_string = CString::new(prompt.clone()).unwrap();
-            reverse_prompt.push(c_string.as_ptr());
-            c_strings.push(c_string);
+            bank ^= 1;
+            let (mut tokens, mut pos, mut seqs) = (vec![], vec![], vec![]);
+            for (i, beam) in search.live_mut().enumerate() {
+                let seq = (1 + bank * width + i) as i32;
+                unsafe {
+                    llama_binding_kv_seq_rm(self.state, seq, -1, -1);
+                    llama_binding_kv_seq_cp(self.state, beam.seq, seq, -1, -1);
+                }
+                beam.seq = seq;
+                tokens.push(beam.tokens[beam.tokens.len() - 1]);
+                pos.push((prompt.len() + beam.tokens.len() - 1) as i32);
+                seqs.push(seq);
+            }
+            for i in 0..width {
+                let seq = (1 + (bank ^ 1) * width + i) as i32;
+                unsafe { llama_binding_kv_seq_rm(self.state, seq, -1, -1) };
+            }
+
+            logits.resize(tokens.len() * n_vocab, 0.0);
+            self.decode(&tokens, &pos, &seqs, tokens.len(), &mut logits)?;
         }
+        let gen_ms = gen_start.elapsed().as_secs_f64() * 1000.0;
 
-        if !reverse_prompt.is_empty() {
-            pass = reverse_prompt.as_mut_ptr();
+        // only the prompt stays in the context
+        for seq in 1..=2 * width {
+            unsafe { llama_binding_kv_seq_rm(self.state, seq as i32, -1, -1) };
         }
+        kv_tokens.extend(&prompt);
+        drop(kv_tokens);
+
+        let mut beams = search
+            .beams
+            .iter()
+            .map(|beam| {
+                let end = beam.tokens.len() - beam.finished as usize;
+                Ok(Beam {
+                    text: self
+                        .detokenize(&beam.tokens[..end])?
+                        .trim_start()
+                        .to_string(),
+                    tokens: beam.tokens.clone(),
+                    logprob: beam.logprob,
+                    score: search.score(beam),
+                    finished: beam.finished,
+                })
+            })
+            .collect::<Result<Vec<_>, LlamaError>>()?;
+        if !opts.all_beams {
+            beams.truncate(1);
+        }
+        let Some(best) = beams.first() else {
+            return Err(LlamaError::InvalidOptions(
+                "beam search found no tokens to generate".to_string(),
+            ));
+        };
 
-        let mut out = Vec::with_capacity(opts.tokens as usize);
-        let logit_bias_cstr = CString::new(opts.logit_bias.clone()).unwrap();
-        let logit_bias = logit_bias_cstr.as_ptr();
-        let path_prompt_cache_cstr = CString::new(opts.path_prompt_cache.clone()).unwrap();
+        if !best.text.is_empty() {
+            forward_token(self.state, best.text.clone());
+        }
+        let completion_tokens = best.tokens.len();
+
+        Ok(PredictResult {
+            text: best.text.clone(),
+            finish_reason: if best.finished {
+                FinishReason::Eos
+            } else {
+                FinishReason::Length
+            },
+            prompt_tokens: prompt.len(),
+            completion_tokens,
+            cached_tokens: cached,
+            prompt_eval_ms,
+            gen_ms,
+            tokens_per_sec: if gen_ms > 0.0 {
+                completion_tokens as f64 * 1000.0 / gen_ms
+            } else {
+                0.0
+            },
+            logprobs: None,
+            json: None,
+            beams: Some(beams),
+            speculative: None,
+        })
+    }
+
+    /// Greedy generation sped up by the draft model: the draft proposes
+    /// `opts.draft_tokens` tokens, the model checks them in one batch and keeps
+    /// those it would have picked itself. The output is the same as without the
+    /// draft model, as long as `prompt` and `opts.tokens` fit in the context.
+    fn speculative_predict(
+        &self,
+        draft: &LLama,
+        prompt: Vec<i32>,
+        opts: PredictOptions,
+    ) -> Result<PredictResult, LlamaError> {
+        let _context = self.context.lock();
+        let mut opts = opts;
+        opts.validate()?;
+        if let Some(adapters) = &opts.lora_adapters {
+            self.set_lora_adapters(adapters)?;
+        }
+
+        let n_ctx = self.context_size.min(draft.context_size);
+        if prompt.len() + 4 > n_ctx {
+            return Err(LlamaError::ContextOverflow {
+                tokens: prompt.len(),
+                context_size: n_ctx,
+            });
+        }
+
+        // the model checks the token it picked and every proposal in one batch
+        let n_batch = self.n_batch.min(draft.n_batch);
+        let max_draft = opts.draft_tokens.min(n_batch.saturating_sub(1));
+        let n_vocab = self.n_vocab() as usize;
+        let eos = self.token_eos();
+        let bias = self.bias_vector(&opts)?;
+        let max_tokens = if opts.tokens > 0 {
+            opts.tokens as usize
+        } else {
+            usize::MAX
+        };
+        // picks the same tokens as llama_predict's greedy path, whose repetition
+        // penalty also covers the newline token
+        let mut verifier = if opts.uses_extended_samplers() {
+            self.sampler_chain(&opts)
+        } else {
+            SamplerChain::new(Selection::Greedy, 0).with(RepetitionPenalty {
+                last_n: repeat_last_n(opts.repeat, self.context_size),
+                repeat: opts.penalty,
+                frequency: opts.frequency_penalty,
+                presence: opts.presence_penalty,
+                exempt: vec![],
+            })
+        };
+        let mut pick = |row: &[f32], history: &[i32]| {
+            let mut candidates = Candidates::new(
+                row.iter()
+                    .zip(&bias)
+                    .enumerate()
+                    .map(|(id, (logit, b))| Candidate {
+                        id: id as i32,
+                        logit: logit + b,
+                        p: 0.0,
+                    })
+                    .collect(),
+            );
+            verifier.sample(&mut candidates, history).unwrap_or(eos)
+        };
+
+        let _draft_context = draft.context.lock();
+        let _callback = CallbackGuard::register(self.state, opts.token_callback.take());
+        open_stream(self.state, &opts)?;
+
+        let mut kv_tokens = self.kv_tokens.lock().unwrap();
+        let mut draft_kv_tokens = draft.kv_tokens.lock().unwrap();
+        unsafe {
+            llama_binding_set_n_threads(self.state, opts.threads);
+            llama_binding_set_n_threads(draft.state, opts.threads);
+        }
+
+        // like llama_predict's last_n_tokens, the penalties see zeros before the
+        // prompt, as many as fill the model's context
+        let pad = self.context_size - prompt.len();
+        let mut history = vec![0; pad];
+        history.extend(&prompt);
+        let mut stats = SpeculativeStats {
+            drafted: 0,
+            accepted: 0,
+        };
+        let mut completion_tokens = 0;
+        let mut cached = 0;
+        let mut prompt_eval_ms = 0.0;
+        let gen_start = Instant::now();
+
+        let mut run = || -> Result<FinishReason, LlamaError> {
+            let prompt_start = Instant::now();
+            // the model's logits for the token after `history`, then for each draft token
+            let mut logits = vec![0.0f32; n_vocab];
+            let mut draft_logits = vec![0.0f32; n_vocab];
+            cached = self.eval_prompt(&mut kv_tokens, &prompt, opts.batch, &mut logits)?;
+            draft.eval_prompt(&mut draft_kv_tokens, &prompt, opts.batch, &mut draft_logits)?;
+            let mut draft_n_past = prompt.len();
+            prompt_eval_ms = prompt_start.elapsed().as_secs_f64() * 1000.0;
+
+            loop {
+                let id = pick(&logits[..n_vocab], &history);
+                history.push(id);
+                let n_tokens = history.len() - pad;
+                let n_draft = max_draft.min(n_ctx - n_tokens);
+
+                // catch the draft up with the sequence, then let it continue greedily
+                let mut proposals = vec![];
+                if n_draft > 0 {
+                    let pending = &history[pad + draft_n_past..];
+                    let pos: Vec<i32> = (draft_n_past..n_tokens).map(|p| p as i32).collect();
+                    draft.decode(pending, &pos, &vec![0; pending.len()], 1, &mut draft_logits)?;
+                    draft_n_past = n_tokens;
+                    loop {
+                        let proposal = argmax(&draft_logits, &bias);
+                        proposals.push(proposal);
+                        if proposals.len() == n_draft {
+                            break;
+                        }
+                        draft.decode(
+                            &[proposal],
+                            &[draft_n_past as i32],
+                            &[0],
+                            1,
+                            &mut draft_logits,
+                        )?;
+                        draft_n_past += 1;
+                    }
+                }
+
+                // one batch gives the model's logits after `id` and after every proposal
+                let mut batch = vec![id];
+                batch.extend(&proposals);
+                let pos: Vec<i32> = (n_tokens - 1..n_tokens + proposals.len())
+                    .map(|p| p as i32)
+                    .collect();
+                logits.resize(batch.len() * n_vocab, 0.0);
+                self.decode(
+                    &batch,
+                    &pos,
+                    &vec![0; batch.len()],
+                    batch.len(),
+                    &mut logits,
+                )?;
+
+                let mut n_accepted = 0;
+                for (i, &proposal) in proposals.iter().enumerate() {
+                    if pick(&logits[i * n_vocab..(i + 1) * n_vocab], &history) != proposal {
+                        break;
+                    }
+                    history.push(proposal);
+                    n_accepted += 1;
+                }
+                stats.drafted += proposals.len();
+                stats.accepted += n_accepted;
+                logits.copy_within(n_accepted * n_vocab..(n_accepted + 1) * n_vocab, 0);
+
+                let mut finish = None;
+                let round = history.len() - n_accepted - 1;
+                for i in round..history.len() {
+                    let token = history[i];
+                    completion_tokens += 1;
+                    let piece =
+                        String::from_utf8_lossy(&token_piece(self.state, token)).to_string();
+                    finish = if !stream_piece(self.state, &piece) {
+                        Some(FinishReason::Cancelled)
+                    } else if token == eos {
+                        Some(FinishReason::Eos)
+                    } else if completion_tokens >= max_tokens || i + 1 - pad >= n_ctx {
+                        Some(FinishReason::Length)
+                    } else {
+                        None
+                    };
+                    if finish.is_some() {
+                        history.truncate(i + 1);
+                        break;
+                    }
+                }
+
+                // drop what the model and the draft evaluated past the accepted tokens
+                let n_tokens = history.len() - pad;
+                draft_n_past = draft_n_past.min(n_tokens);
+                unsafe {
+                    llama_binding_kv_seq_rm(self.state, 0, n_tokens as i32, -1);
+                    llama_binding_kv_seq_rm(draft.state, 0, draft_n_past as i32, -1);
+                }
+                if let Some(finish) = finish {
+                    *draft_kv_tokens = history[pad..pad + draft_n_past].to_vec();
+                    *kv_tokens = history[pad..].to_vec();
+                    return Ok(finish);
+                }
+            }
+        };
+        let finish = run();
+        drop(kv_tokens);
+        drop(draft_kv_tokens);
+        let gen_ms = gen_start.elapsed().as_secs_f64() * 1000.0 - prompt_eval_ms;
+
+        let stream = STREAMS.lock().unwrap().remove(&(self.state as usize));
+        let (res, finish_reason) = close_stream(self.state, stream, finish?);
+
+        Ok(PredictResult {
+            text: res.trim_start().to_string(),
+            finish_reason,
+            prompt_tokens: prompt.len(),
+            completion_tokens,
+            cached_tokens: cached,
+            prompt_eval_ms,
+            gen_ms,
+            tokens_per_sec: if gen_ms > 0.0 {
+                completion_tokens as f64 * 1000.0 / gen_ms
+            } else {
+                0.0
+            },
+            logprobs: None,
+            json: None,
+            beams: None,
+            speculative: Some(stats),
+        })
+    }
+
+    // Dense logit bias for the searches done on the Rust side.
+    fn bias_vector(&self, opts: &PredictOptions) -> Result<Vec<f32>, LlamaError> {
+        let mut bias = vec![0.0f32; self.n_vocab() as usize];
+        let (bias_tokens, bias_values) = self.logit_bias_arrays(opts)?;
+        for (token, value) in bias_tokens.into_iter().zip(bias_values) {
+            bias[token as usize] = value;
+        }
+
+        Ok(bias)
+    }
+
+    // Evaluates `prompt` in sequence 0 after the prefix it shares with the
+    // context, and returns the length of that prefix. At least the last prompt
+    // token is evaluated, its logits go to `logits`. `kv_tokens` is left empty
+    // for the caller to set once the context holds what it should.
+    fn eval_prompt(
+        &self,
+        kv_tokens: &mut Vec<i32>,
+        prompt: &[i32],
+        batch: i32,
+        logits: &mut [f32],
+    ) -> Result<usize, LlamaError> {
+        let _context = self.context.lock();
+        let cached = kv_tokens
+            .iter()
+            .zip(prompt)
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(prompt.len() - 1);
+        kv_tokens.clear();
+        unsafe { llama_binding_kv_seq_rm(self.state, -1, cached as i32, -1) };
+
+        // llama_decode takes at most n_batch tokens at once
+        let batch = if batch > 0 {
+            (batch as usize).min(self.n_batch)
+        } else {
+            self.n_batch
+        };
+        let mut n_past = cached;
+        for chunk in prompt[cached..].chunks(batch.max(1)) {
+            let pos: Vec<i32> = (n_past..n_past + chunk.len()).map(|p| p as i32).collect();
+            let n_logits = (n_past + chunk.len() == prompt.len()) as usize;
+            self.decode(chunk, &pos, &vec![0; chunk.len()], n_logits, logits)?;
+            n_past += chunk.len();
+        }
+
+        Ok(cached)
+    }
+
+    // Evaluates `tokens` at positions `pos` in sequences `seqs`, and copies the
+    // logits of the last `n_logits` tokens into `logits`.
+    fn decode(
+        &self,
+        tokens: &[i32],
+        pos: &[i32],
+        seqs: &[i32],
+        n_logits: usize,
+        logits: &mut [f32],
+    ) -> Result<(), LlamaError> {
+        let _context = self.context.lock();
+        let ret = unsafe {
+            llama_binding_decode(
+                self.state,
+                tokens.as_ptr(),
+                pos.as_ptr(),
+                seqs.as_ptr(),
+                tokens.len() as i32,
+                n_logits as i32,
+                logits.as_mut_ptr(),
+            )
+        };
+        if ret != 0 {
+            return Err(LlamaError::Eval { code: ret });
+        }
+
+        Ok(())
+    }
+
+    /// Generates `opts.n` completions of `text`, one after the other, with the
+    /// seed `opts.seed + i` (a random base seed when `opts.seed` isn't
+    /// positive). The prompt is evaluated once, later completions reuse its KV
+    /// entries. The token callback receives the completions one after the other.
+    pub fn predict_n(
+        &self,
+        text: String,
+        opts: PredictOptions,
+    ) -> Result<Vec<PredictResult>, LlamaError> {
+        let _context = self.context.lock();
+        let mut opts = opts;
+        opts.validate()?;
+
+        let base_seed = if opts.seed > 0 {
+            opts.seed
+        } else {
+            rand::thread_rng().gen_range(1..i32::MAX / 2)
+        };
+        let mut results = Vec::with_capacity(opts.n);
+        for i in 0..opts.n {
+            opts.seed = (base_seed.wrapping_add(i as i32) & i32::MAX).max(1);
+            let mut res = self.generate(text.clone(), &[], -1, &mut opts)?;
+            res.text = res.text.trim_start().to_string();
+            results.push(res);
+        }
+
+        Ok(results)
+    }
+
+    /// Generates the code between `prefix` and `suffix` with a model trained for
+    /// fill-in-the-middle, such as CodeLlama or StarCoder. Generation ends at the
+    /// model's end-of-middle token.
+    pub fn infill(
+        &self,
+        prefix: &str,
+        suffix: &str,
+        opts: PredictOptions,
+    ) -> Result<PredictResult, LlamaError> {
+        let n_vocab = self.n_vocab();
+        let (pre, suf, mid, eot) = (
+            self.token_prefix(),
+            self.token_suffix(),
+            self.token_middle(),
+            self.token_eot(),
+        );
+        if [pre, suf, mid, eot]
+            .iter()
+            .any(|&token| token < 0 || token >= n_vocab)
+        {
+            return Err(LlamaError::InfillUnsupported);
+        }
+
+        let mut tokens = vec![self.token_bos(), pre];
+        tokens.extend(self.tokenize(prefix, false)?);
+        tokens.push(suf);
+        tokens.extend(self.tokenize(suffix, false)?);
+        tokens.push(mid);
+
+        let mut opts = opts;
+        self.generate(String::new(), &tokens, eot, &mut opts)
+    }
+
+    // Runs llama_predict on `text`, or on `prompt_tokens` when there are any.
+    // Sampling `end_token` ends generation like EOS, -1 for none. The sampler
+    // and logprobs callback are taken out of `opts`, the token callback is put
+    // back once generation succeeds so it can be reused for another call.
+    fn generate(
+        &self,
+        text: String,
+        prompt_tokens: &[i32],
+        end_token: i32,
+        opts: &mut PredictOptions,
+    ) -> Result<PredictResult, LlamaError> {
+        let _context = self.context.lock();
+        let rules = match &opts.response_schema {
+            Some(_) if opts.grammar.is_some() => {
+                return Err(LlamaError::InvalidOptions(
+                    "grammar and response_schema can't be used together".to_string(),
+                ))
+            }
+            Some(schema) => Some(json_schema::schema_to_grammar(schema)?),
+            None => opts.grammar.clone(),
+        };
+        if let Some(rules) = &rules {
+            grammar::validate(rules)?;
+        }
+        opts.validate()?;
+        if let Some(adapters) = &opts.lora_adapters {
+            self.set_lora_adapters(adapters)?;
+        }
+        if opts.sampler.is_none() && opts.uses_extended_samplers() {
+            opts.sampler = Some(self.sampler_chain(opts));
+        }
+
+        let c_str = prompt_c_string(&text)?;
+
+        let input = c_str.as_ptr();
+
+        if opts.tokens == 0 {
+            opts.tokens = 99999999;
+        }
+
+        let (logit_bias_tokens, logit_bias_values) = self.logit_bias_arrays(opts)?;
+        let path_prompt_cache_cstr = c_string(&opts.path_prompt_cache, "path_prompt_cache")?;
         let path_prompt_cache = path_prompt_cache_cstr.as_ptr();
-        let main_gpu_cstr = CString::new(opts.main_gpu.clone()).unwrap();
+        let main_gpu_cstr = c_string(&opts.main_gpu, "main_gpu")?;
         let main_gpu = main_gpu_cstr.as_ptr();
-        let tensor_split_cstr = CString::new(opts.tensor_split.clone()).unwrap();
+        let tensor_split_cstr = c_string(&opts.tensor_split, "tensor_split")?;
         let tensor_split = tensor_split_cstr.as_ptr();
+        let grammar_cstr = c_string(rules.as_deref().unwrap_or_default(), "grammar")?;
+        let grammar = grammar_cstr.as_ptr();
+
+        // once the stream is open nothing may fail until llama_predict returns,
+        // or the entries registered for this context would outlive the call
+        let callback = CallbackGuard::register(self.state, opts.token_callback.take());
+
+        // a streaming logprobs callback implies at least the sampled token's logprob
+        let n_probs = match (opts.logprobs, &opts.logprobs_callback) {
+            (Some(n), _) => n as i32,
+            (None, Some(_)) => 0,
+            (None, None) => -1,
+        };
+        open_stream(self.state, opts)?;
+        let custom_sampler = opts.sampler.is_some();
+        if let Some(sampler) = opts.sampler.take() {
+            SAMPLERS
+                .lock()
+                .unwrap()
+                .insert(self.state as usize, sampler);
+        }
+        if n_probs >= 0 {
+            LOGPROBS.lock().unwrap().insert(
+                self.state as usize,
+                LogprobsSink {
+                    tokens: vec![],
+                    callback: opts.logprobs_callback.take(),
+                },
+            );
+        }
+
+        let mut out = Vec::with_capacity(opts.tokens as usize);
 
         unsafe {
             let params = llama_allocate_params(
@@ -410,8 +1665,8 @@ impl LLama {
                 opts.f16_kv,
                 opts.batch,
                 opts.n_keep,
-                pass,
-                reverse_count as i32,
+                std::ptr::null_mut(),
+                0,
                 opts.tail_free_sampling_z,
                 opts.typical_p,
                 opts.frequency_penalty,
@@ -420,7 +1675,9 @@ impl LLama {
                 opts.mirostat_eta,
                 opts.mirostat_tau,
                 opts.penalize_nl,
-                logit_bias,
+                logit_bias_tokens.as_ptr(),
+                logit_bias_values.as_ptr(),
+                logit_bias_tokens.len() as i32,
                 path_prompt_cache,
                 opts.prompt_cache_all,
                 opts.m_lock,
@@ -428,28 +1685,105 @@ impl LLama {
                 main_gpu,
                 tensor_split,
                 opts.prompt_cache_ro,
+                grammar,
+                n_probs,
             );
 
-            let ret = llama_predict(params, self.state, out.as_mut_ptr(), opts.debug_mode);
+            let mut stats = llama_binding_predict_stats {
+                finish_reason: 0,
+                prompt_tokens: 0,
+                completion_tokens: 0,
+                cached_tokens: 0,
+                prompt_eval_ms: 0.0,
+                gen_ms: 0.0,
+            };
+            let mut kv_tokens = self.kv_tokens.lock().unwrap();
+            let mut n_kv_tokens = kv_tokens.len() as i32;
+            let kv_tokens_cap = kv_tokens.len().max(self.context_size);
+            kv_tokens.resize(kv_tokens_cap, 0);
+
+            let ret = llama_predict(
+                params,
+                self.state,
+                out.as_mut_ptr(),
+                opts.debug_mode,
+                kv_tokens.as_mut_ptr(),
+                n_kv_tokens,
+                kv_tokens_cap as i32,
+                &mut n_kv_tokens,
+                custom_sampler,
+                prompt_tokens.as_ptr(),
+                prompt_tokens.len() as i32,
+                end_token,
+                &mut stats,
+            );
+            // the callback drops the chain when a stage panics
+            let sampler_panicked = SAMPLERS
+                .lock()
+                .unwrap()
+                .remove(&(self.state as usize))
+                .is_none()
+                && custom_sampler;
+            let stream = STREAMS.lock().unwrap().remove(&(self.state as usize));
+            let mut logprobs = LOGPROBS
+                .lock()
+                .unwrap()
+                .remove(&(self.state as usize))
+                .map(|sink| sink.tokens);
 
             if ret != 0 {
-                return Err("Failed to predict".into());
+                kv_tokens.clear();
+                return Err(match ret {
+                    2 => GrammarError::Rejected.into(),
+                    3 => LlamaError::ContextOverflow {
+                        tokens: stats.prompt_tokens as usize,
+                        context_size: self.context_size,
+                    },
+                    code => LlamaError::Eval { code },
+                });
             }
 
-            llama_free_params(params);
+            kv_tokens.truncate(n_kv_tokens as usize);
+            drop(kv_tokens);
 
-            let c_str: &CStr = CStr::from_ptr(out.as_mut_ptr());
-            let mut res: String = c_str.to_str().unwrap().to_owned();
-
-            res = res.trim_start().to_string();
-            res = res.trim_start_matches(&text).to_string();
-            res = res.trim_start_matches('\n').to_string();
+            llama_free_params(params);
+            if sampler_panicked {
+                return Err(LlamaError::SamplerPanicked);
+            }
 
-            for s in &opts.stop_prompts {
-                res = res.trim_end_matches(s).to_string();
+            let generated = stream.as_ref().map_or(0, |stream| stream.generated);
+            let (res, finish_reason) =
+                close_stream(self.state, stream, finish_reason(stats.finish_reason));
+            opts.token_callback = callback.release();
+            if let Some(logprobs) = &mut logprobs {
+                if res.len() < generated {
+                    trim_logprobs(logprobs, res.len());
+                }
             }
 
-            Ok(res)
+            let json = match opts.response_schema {
+                Some(_) => serde_json::from_str(res.trim()).ok(),
+                None => None,
+            };
+
+            Ok(PredictResult {
+                text: res,
+                finish_reason,
+                prompt_tokens: stats.prompt_tokens as usize,
+                completion_tokens: stats.completion_tokens as usize,
+                cached_tokens: stats.cached_tokens as usize,
+                prompt_eval_ms: stats.prompt_eval_ms,
+                gen_ms: stats.gen_ms,
+                tokens_per_sec: if stats.gen_ms > 0.0 {
+                    stats.completion_tokens as f64 * 1000.0 / stats.gen_ms
+                } else {
+                    0.0
+                },
+                logprobs,
+                json,
+                beams: None,
+                speculative: None,
+            })
         }
     }
 }
@@ -460,6 +1794,149 @@ impl Drop for LLama {
     }
 }
 
+fn token_piece(state: *mut c_void, token: i32) -> Vec<u8> {
+    let mut buf = vec![0u8; 8];
+    unsafe {
+        let mut n = llama_binding_token_to_piece(
+            state,
+            token,
+            buf.as_mut_ptr() as *mut c_char,
+            buf.len() as i32,
+        );
+        if n < 0 {
+            buf.resize(-n as usize, 0);
+            n = llama_binding_token_to_piece(
+                state,
+                token,
+                buf.as_mut_ptr() as *mut c_char,
+                buf.len() as i32,
+            );
+        }
+        buf.truncate(n.max(0) as usize);
+    }
+
+    buf
+}
+
+// CString::new fails on interior NUL bytes, which C strings can't hold.
+fn c_string(value: &str, name: &str) -> Result<CString, LlamaError> {
+    CString::new(value)
+        .map_err(|_| LlamaError::InvalidOptions(format!("{} contains a NUL byte", name)))
+}
+
+fn prompt_c_string(prompt: &str) -> Result<CString, LlamaError> {
+    CString::new(prompt)
+        .map_err(|_| LlamaError::InvalidPrompt("prompt contains a NUL byte".to_string()))
+}
+
+// Whether predict decodes greedily without anything the speculative path
+// doesn't reproduce.
+fn is_plain_greedy(opts: &PredictOptions) -> bool {
+    opts.temperature <= 0.0
+        && opts.n <= 1
+        && opts.mirostat == 0
+        && opts.xtc.is_none()
+        && opts.grammar.is_none()
+        && opts.response_schema.is_none()
+        && opts.sampler.is_none()
+        && opts.logprobs.is_none()
+        && opts.logprobs_callback.is_none()
+        && opts.path_prompt_cache.is_empty()
+}
+
+fn argmax(logits: &[f32], bias: &[f32]) -> i32 {
+    logits
+        .iter()
+        .zip(bias)
+        .map(|(logit, b)| logit + b)
+        .enumerate()
+        .fold((0, f32::NEG_INFINITY), |best, (id, logit)| {
+            if logit > best.1 {
+                (id, logit)
+            } else {
+                best
+            }
+        })
+        .0 as i32
+}
+
+// Starts matching the stop conditions of `opts` against the text generated for
+// `state`. Stop prompts are matched here rather than in llama.cpp, so that no
+// part of them reaches the token callback.
+fn open_stream(state: *mut c_void, opts: &PredictOptions) -> Result<(), LlamaError> {
+    let conditions = StopConditions::new(opts)?;
+    STREAMS.lock().unwrap().insert(
+        state as usize,
+        TokenStream {
+            matcher: StopMatcher::new(opts.stop_prompts.clone()),
+            conditions,
+            output: String::new(),
+            generated: 0,
+        },
+    );
+
+    Ok(())
+}
+
+// Returns the generated text without the stop matches, and why generation
+// stopped given that the generation loop ended with `finish_reason`.
+fn close_stream(
+    state: *mut c_void,
+    stream: Option<TokenStream>,
+    finish_reason: FinishReason,
+) -> (String, FinishReason) {
+    let Some(mut stream) = stream else {
+        return (String::new(), finish_reason);
+    };
+    let mut finish_reason = finish_reason;
+
+    // text held back for a stop prompt that never completed
+    let rest = stream.matcher.flush();
+    if !rest.is_empty() {
+        forward_token(state, rest.clone());
+    }
+    stream.output.push_str(&rest);
+    if stream.matcher.stopped() {
+        finish_reason = FinishReason::Stop;
+    }
+
+    if let Some(stop) = &stream.conditions {
+        let mut len = stream.output.len().saturating_sub(stop.trim_len());
+        while !stream.output.is_char_boundary(len) {
+            len -= 1;
+        }
+        stream.output.truncate(len);
+        finish_reason = stop.reason().unwrap_or(finish_reason);
+    }
+
+    (stream.output, finish_reason)
+}
+
+fn finish_reason(code: i32) -> FinishReason {
+    match code as u32 {
+        LLAMA_BINDING_FINISH_EOS => FinishReason::Eos,
+        LLAMA_BINDING_FINISH_STOP => FinishReason::Stop,
+        LLAMA_BINDING_FINISH_CANCELLED => FinishReason::Cancelled,
+        _ => FinishReason::Length,
+    }
+}
+
+// Reads a string from a C function with snprintf semantics, growing the buffer if needed.
+fn read_c_string(read: impl Fn(*mut c_char, usize) -> i32) -> String {
+    let mut buf = vec![0u8; 256];
+    let n = read(buf.as_mut_ptr() as *mut c_char, buf.len());
+    if n < 0 {
+        return String::new();
+    }
+    if n as usize >= buf.len() {
+        buf.resize(n as usize + 1, 0);
+        read(buf.as_mut_ptr() as *mut c_char, buf.len());
+    }
+    buf.truncate(n as usize);
+
+    String::from_utf8_lossy(&buf).to_string()
+}
+
 fn set_callback(
     state: *mut c_void,
     callback: Option<Box<dyn Fn(String) -> bool + Send + 'static>>,
@@ -473,16 +1950,335 @@ fn set_callback(
     }
 }
 
+// Registers the token callback of one call for a context, and unregisters it
+// when dropped so it can't outlive the call.
+struct CallbackGuard {
+    state: *mut c_void,
+    registered: bool,
+}
+
+impl CallbackGuard {
+    fn register(state: *mut c_void, callback: Option<Callback>) -> Self {
+        let registered = callback.is_some();
+        if registered {
+            set_callback(state, callback);
+        }
+        Self { state, registered }
+    }
+
+    // Unregisters the callback and hands it back.
+    fn release(mut self) -> Option<Callback> {
+        if !self.registered {
+            return None;
+        }
+        self.registered = false;
+        CALLBACKS.lock().unwrap().remove(&(self.state as usize))
+    }
+}
+
+impl Drop for CallbackGuard {
+    fn drop(&mut self) {
+        if self.registered {
+            set_callback(self.state, None);
+        }
+    }
+}
+
+struct LogprobsSink {
+    tokens: Vec<TokenLogprobs>,
+    callback: Option<LogprobsCallback>,
+}
+
+// Whether any matrix of the GGUF file at `path` is stored in a quantized type.
+// Files that can't be read count as quantized.
+fn is_quantized(path: &str) -> bool {
+    GgufFile::open(path).map_or(true, |gguf| {
+        gguf.tensors
+            .iter()
+            .any(|tensor| tensor.dims.len() > 1 && !matches!(tensor.ggml_type, 0 | 1))
+    })
+}
+
+/// Refuses to load a model that won't fit in the available memory. Layers
+/// offloaded to the GPU don't take RAM, so the check is skipped when there are
+/// any.
+fn check_memory(model: &str, opts: &ModelOptions) -> Result<(), LlamaError> {
+    if opts.n_gpu_layers > 0 || opts.vocab_only {
+        return Ok(());
+    }
+    let Some(available) = available_memory() else {
+        return Ok(());
+    };
+    let needed = MemoryEstimate::for_model_or_file_size(model, opts.context_size);
+    slog::info!(LOGGER, "model {} needs about {}", model, needed);
+
+    if needed.total() > available {
+        return Err(LlamaError::InsufficientMemory {
+            path: model.to_string(),
+            needed,
+            context_size: opts.context_size,
+            available,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct LogLine {
+    level: c_int,
+    text: String,
+}
+
+impl LogLine {
+    // Appends `text` logged at `level` and returns the lines it completes, with
+    // their level. Text pending at another level is a line of its own.
+    fn push(&mut self, level: c_int, text: &str) -> Vec<(c_int, String)> {
+        let mut complete = vec![];
+        if level != self.level && !self.text.is_empty() {
+            complete.push((self.level, std::mem::take(&mut self.text)));
+        }
+        self.level = level;
+        self.text.push_str(text);
+        if let Some(end) = self.text.rfind('\n') {
+            let lines: String = self.text.drain(..=end).collect();
+            complete.extend(lines.lines().map(|line| (level, line.to_string())));
+        }
+
+        complete
+            .into_iter()
+            .map(|(level, line)| (level, line.trim_end().to_string()))
+            .filter(|(_, line)| !line.is_empty())
+            .collect()
+    }
+}
+
+#[no_mangle]
+extern "C" fn logCallback(level: c_int, text: *const c_char) {
+    let text = unsafe { CStr::from_ptr(text) }.to_string_lossy();
+    let complete = LOG_LINE.with(|line| line.borrow_mut().push(level, &text));
+    for (level, message) in complete {
+        match level as u32 {
+            LLAMA_BINDING_LOG_ERROR => slog::error!(LOGGER, "llama.cpp: {}", message),
+            LLAMA_BINDING_LOG_WARN => slog::warn!(LOGGER, "llama.cpp: {}", message),
+            LLAMA_BINDING_LOG_INFO => slog::info!(LOGGER, "llama.cpp: {}", message),
+            _ => slog::debug!(LOGGER, "llama.cpp: {}", message),
+        }
+    }
+}
+
+#[no_mangle]
+extern "C" fn loadProgressCallback(user_data: *mut c_void, progress: f32) {
+    let callback = unsafe { &mut *(user_data as *mut &mut dyn FnMut(f32)) };
+    callback(progress);
+}
+
 #[no_mangle]
 extern "C" fn tokenCallback(state: *mut c_void, token: *const c_char) -> bool {
+    let c_str: &CStr = unsafe { CStr::from_ptr(token) };
+    stream_piece(state, &c_str.to_string_lossy())
+}
+
+// Passes a generated piece through the stop conditions to the token callback,
+// returns false to stop generation.
+fn stream_piece(state: *mut c_void, piece: &str) -> bool {
+    let mut streams = STREAMS.lock().unwrap();
+    let Some(stream) = streams.get_mut(&(state as usize)) else {
+        drop(streams);
+        return forward_token(state, piece.to_string());
+    };
+
+    stream.generated += piece.len();
+    if let Some(conditions) = &mut stream.conditions {
+        if !conditions.check(piece) {
+            return false;
+        }
+    }
+    let safe = stream.matcher.push(piece);
+    stream.output.push_str(&safe);
+    let stopped = stream.matcher.stopped();
+    drop(streams);
+
+    if !safe.is_empty() && !forward_token(state, safe) {
+        return false;
+    }
+
+    !stopped
+}
+
+// Hands streamed text to the token callback, returns false to stop generation.
+fn forward_token(state: *mut c_void, text: String) -> bool {
     let mut callbacks = CALLBACKS.lock().unwrap();
 
     if let Some(callback) = callbacks.get_mut(&(state as usize)) {
-        let c_str: &CStr = unsafe { CStr::from_ptr(token) };
-        let str_slice = c_str.to_string_lossy();
-
-        return callback(str_slice.to_string());
+        return callback(text);
     }
 
     true
 }
+
+#[no_mangle]
+extern "C" fn logprobsCallback(
+    state: *mut c_void,
+    token: i32,
+    logprob: f32,
+    top_tokens: *const i32,
+    top_logprobs: *const f32,
+    n_top: i32,
+) {
+    // the callback runs without the lock, so a slow one doesn't hold up other
+    // contexts and one that starts a predict doesn't deadlock
+    let callback = match LOGPROBS.lock().unwrap().get_mut(&(state as usize)) {
+        Some(sink) => sink.callback.take(),
+        None => return,
+    };
+
+    let to_logprob = |id: i32, logprob: f32| TokenLogprob {
+        id,
+        text: String::from_utf8_lossy(&token_piece(state, id)).to_string(),
+        logprob,
+    };
+    let (top_tokens, top_logprobs) = if n_top > 0 {
+        unsafe {
+            (
+                std::slice::from_raw_parts(top_tokens, n_top as usize),
+                std::slice::from_raw_parts(top_logprobs, n_top as usize),
+            )
+        }
+    } else {
+        (&[][..], &[][..])
+    };
+    let logprobs = TokenLogprobs {
+        token: to_logprob(token, logprob),
+        top: top_tokens
+            .iter()
+            .zip(top_logprobs)
+            .map(|(&id, &logprob)| to_logprob(id, logprob))
+            .collect(),
+    };
+
+    if let Some(callback) = &callback {
+        callback(&logprobs);
+    }
+    if let Some(sink) = LOGPROBS.lock().unwrap().get_mut(&(state as usize)) {
+        sink.callback = callback;
+        sink.tokens.push(logprobs);
+    }
+}
+
+#[no_mangle]
+extern "C" fn samplerCallback(
+    state: *mut c_void,
+    candidates: *mut c_void,
+    n_candidates: usize,
+    history: *const i32,
+    n_history: i32,
+) -> i32 {
+    // the chain runs without the lock, so a panic can't poison it
+    let Some(mut sampler) = SAMPLERS.lock().unwrap().remove(&(state as usize)) else {
+        return -1;
+    };
+    let (candidates, history) = unsafe {
+        (
+            std::slice::from_raw_parts(candidates as *const Candidate, n_candidates),
+            std::slice::from_raw_parts(history, n_history as usize),
+        )
+    };
+
+    // unwinding into llama.cpp would abort, a panicking stage ends generation
+    // instead and the chain isn't put back
+    let sampled = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        sampler.sample(&mut Candidates::new(candidates.to_vec()), history)
+    }));
+    match sampled {
+        Ok(token) => {
+            SAMPLERS.lock().unwrap().insert(state as usize, sampler);
+            token.unwrap_or(-1)
+        }
+        Err(_) => {
+            slog::error!(LOGGER, "a sampler stage panicked, stopping generation");
+            -1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llama::options::XtcOptions;
+
+    fn greedy() -> PredictOptions {
+        PredictOptions {
+            temperature: 0.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn plain_greedy_needs_deterministic_sampling() {
+        assert!(is_plain_greedy(&greedy()));
+        assert!(!is_plain_greedy(&PredictOptions {
+            temperature: 0.8,
+            ..greedy()
+        }));
+        assert!(!is_plain_greedy(&PredictOptions {
+            xtc: Some(XtcOptions::default()),
+            ..greedy()
+        }));
+        assert!(!is_plain_greedy(&PredictOptions {
+            mirostat: 2,
+            ..greedy()
+        }));
+        assert!(!is_plain_greedy(&PredictOptions { n: 2, ..greedy() }));
+        assert!(!is_plain_greedy(&PredictOptions {
+            grammar: Some("root ::= \"a\"".to_string()),
+            ..greedy()
+        }));
+    }
+
+    #[test]
+    fn unquantized_models_can_take_adapters_back_out() {
+        let fixture = |name| format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        // its only matrix is F32
+        assert!(!is_quantized(&fixture("valid.gguf")));
+        assert!(is_quantized(&fixture("missing.gguf")));
+    }
+
+    #[test]
+    fn log_lines_join_pieces() {
+        let mut line = LogLine::default();
+        assert!(line.push(2, "loading ").is_empty());
+        assert_eq!(
+            line.push(2, "model\nmeta: 1\npart"),
+            vec![(2, "loading model".to_string()), (2, "meta: 1".to_string())]
+        );
+        assert_eq!(line.push(2, "ial\n\n"), vec![(2, "partial".to_string())]);
+    }
+
+    #[test]
+    fn log_lines_flush_on_level_change() {
+        let mut line = LogLine::default();
+        assert!(line.push(4, ".....").is_empty());
+        assert_eq!(
+            line.push(2, "failed\n"),
+            vec![(4, ".....".to_string()), (2, "failed".to_string())]
+        );
+    }
+
+    #[test]
+    fn log_lines_are_per_thread() {
+        LOG_LINE.with(|line| line.borrow_mut().push(2, "main "));
+        let other =
+            std::thread::spawn(|| LOG_LINE.with(|line| line.borrow_mut().push(2, "other\n")))
+                .join()
+                .unwrap();
+        assert_eq!(other, vec![(2, "other".to_string())]);
+        let main = LOG_LINE.with(|line| line.borrow_mut().push(2, "thread\n"));
+        assert_eq!(main, vec![(2, "main thread".to_string())]);
+    }
+
+    #[test]
+    fn argmax_adds_the_bias() {
+        assert_eq!(argmax(&[1.0, 3.0, 2.0], &[0.0, 0.0, 0.0]), 1);
+        assert_eq!(argmax(&[1.0, 3.0, 2.0], &[0.0, f32::NEG_INFINITY, 0.0]), 2);
+    }
+}
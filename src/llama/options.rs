@@ -1,6 +1,18 @@
-use super::Callback;
+use std::{collections::HashMap, time::Duration};
 
-#[derive(Debug, Clone)]
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{
+    error::LlamaError, logprobs::LogprobsCallback, sampler::SamplerChain, stop::LoopDetection,
+    Callback,
+};
+
+// The model checks all draft tokens in one batch, which has to fit n_batch.
+const MAX_DRAFT_TOKENS: usize = 64;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ModelOptions {
     pub context_size: i32,
     pub seed: i32,
@@ -15,6 +27,31 @@ pub struct ModelOptions {
     pub main_gpu: String,
     pub tensor_split: String,
     pub numa: bool,
+    /// A smaller model with the same vocabulary, loaded alongside to speed up
+    /// greedy generation with speculative decoding.
+    pub draft_model: Option<String>,
+    /// Merged into the weights once the model is loaded, which then loads
+    /// without mmap so that adapters can be changed later.
+    pub lora_adapters: Vec<LoraAdapter>,
+    /// Unquantized model the adapters are applied against when the model itself
+    /// is quantized, which is more accurate. Empty to use the model's weights.
+    pub lora_base: String,
+}
+
+/// A LoRA adapter file and the scale its deltas are merged with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoraAdapter {
+    pub path: String,
+    pub scale: f32,
+}
+
+impl LoraAdapter {
+    pub fn new(path: impl Into<String>, scale: f32) -> Self {
+        Self {
+            path: path.into(),
+            scale,
+        }
+    }
 }
 
 impl Default for ModelOptions {
@@ -33,10 +70,82 @@ impl Default for ModelOptions {
             n_gpu_layers: 0,
             main_gpu: String::from(""),
             tensor_split: String::from(""),
+            draft_model: None,
+            lora_adapters: vec![],
+            lora_base: String::from(""),
+        }
+    }
+}
+
+/// Scales the temperature between `temperature - range` and
+/// `temperature + range` by the normalized entropy of the candidates.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DynamicTemperature {
+    pub range: f32,
+    pub exponent: f32,
+}
+
+impl Default for DynamicTemperature {
+    fn default() -> Self {
+        Self {
+            range: 0.5,
+            exponent: 1.0,
+        }
+    }
+}
+
+/// "Don't Repeat Yourself" penalty. A token that would extend a sequence already
+/// seen in the last `last_n` tokens (0 for all) by `allowed_length` or more
+/// tokens loses `multiplier * base^(length - allowed_length)` from its logit.
+/// Matching stops at tokens containing one of the `sequence_breakers`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DryOptions {
+    pub multiplier: f32,
+    pub base: f32,
+    pub allowed_length: usize,
+    pub last_n: usize,
+    pub sequence_breakers: Vec<String>,
+}
+
+impl Default for DryOptions {
+    fn default() -> Self {
+        Self {
+            multiplier: 0.8,
+            base: 1.75,
+            allowed_length: 2,
+            last_n: 0,
+            sequence_breakers: vec![
+                String::from("\n"),
+                String::from(":"),
+                String::from("\""),
+                String::from("*"),
+            ],
         }
     }
 }
 
+/// "Exclude Top Choices". With the given probability, removes every candidate
+/// above `threshold` except the least likely of them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct XtcOptions {
+    pub probability: f32,
+    pub threshold: f32,
+}
+
+impl Default for XtcOptions {
+    fn default() -> Self {
+        Self {
+            probability: 0.5,
+            threshold: 0.1,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct PredictOptions {
     pub seed: i32,
     pub threads: i32,
@@ -51,18 +160,68 @@ pub struct PredictOptions {
     pub f16_kv: bool,
     pub debug_mode: bool,
     pub stop_prompts: Vec<String>,
+    /// Regular expressions that stop generation when the output matches, the
+    /// match is cut from the output. Matches longer than 4 KiB may be missed.
+    pub stop_patterns: Vec<String>,
+    /// Stops generation this long after predict was called.
+    pub max_duration: Option<Duration>,
+    /// Stops generation before the output would contain more newlines.
+    pub max_newlines: Option<usize>,
+    pub loop_detection: Option<LoopDetection>,
     pub ignore_eos: bool,
 
     pub tail_free_sampling_z: f32,
     pub typical_p: f32,
+    /// Drops candidates less likely than `min_p` times the most likely one, 0
+    /// disables it.
+    pub min_p: f32,
+    pub dynamic_temperature: Option<DynamicTemperature>,
+    pub dry: Option<DryOptions>,
+    pub xtc: Option<XtcOptions>,
     pub frequency_penalty: f32,
     pub presence_penalty: f32,
     pub mirostat: i32,
     pub mirostat_eta: f32,
     pub mirostat_tau: f32,
     pub penalize_nl: bool,
-    pub logit_bias: String,
+    /// Added to the logit of each token id before sampling, `f32::NEG_INFINITY`
+    /// bans the token.
+    #[serde(with = "logit_bias_format")]
+    pub logit_bias: HashMap<i32, f32>,
+    /// GBNF grammar the output must follow.
+    pub grammar: Option<String>,
+    /// JSON Schema the output must follow, compiled to a grammar at predict time.
+    pub response_schema: Option<Value>,
+    /// Report the log-probability of each generated token together with this
+    /// many top alternatives.
+    pub logprobs: Option<usize>,
+    /// Number of completions `predict_n` generates for the prompt, each
+    /// sampled with its own seed.
+    pub n: usize,
+    /// Decodes with beam search over this many beams instead of sampling when
+    /// it's above 1.
+    pub beam_width: usize,
+    /// Beams are ranked by their log-probability divided by their length raised
+    /// to this, higher values favour longer beams.
+    pub length_penalty: f32,
+    /// Return every beam of a beam search, not only the best one.
+    pub all_beams: bool,
+    /// Tokens the draft model proposes per step when the model has one and
+    /// decoding is greedy, 0 disables speculative decoding. At most `n_batch - 1`
+    /// are proposed. Completions without a `tokens` limit, or that may not fit
+    /// in the context, are generated without the draft model.
+    pub draft_tokens: usize,
+    /// The LoRA adapters to have merged while predicting, attaching, rescaling
+    /// and detaching adapters as needed. `None` keeps the current ones.
+    pub lora_adapters: Option<Vec<LoraAdapter>>,
+    #[serde(skip)]
     pub token_callback: Option<Callback>,
+    #[serde(skip)]
+    pub logprobs_callback: Option<LogprobsCallback>,
+    /// Replaces the built-in sampling chain (and its repetition penalties),
+    /// logit bias and grammar are still applied first.
+    #[serde(skip)]
+    pub sampler: Option<SamplerChain>,
     // pub token_callback: Option<fn(String) -> bool>,
     pub path_prompt_cache: String,
     pub m_lock: bool,
@@ -89,17 +248,36 @@ impl Default for PredictOptions {
             f16_kv: false,
             debug_mode: false,
             stop_prompts: vec![],
+            stop_patterns: vec![],
+            max_duration: None,
+            max_newlines: None,
+            loop_detection: None,
             ignore_eos: false,
             tail_free_sampling_z: 1.0,
             typical_p: 1.0,
+            min_p: 0.0,
+            dynamic_temperature: None,
+            dry: None,
+            xtc: None,
             frequency_penalty: 0.0,
             presence_penalty: 0.0,
             mirostat: 0,
             mirostat_eta: 0.1,
             mirostat_tau: 5.0,
             penalize_nl: false,
-            logit_bias: String::from(""),
+            logit_bias: HashMap::new(),
+            grammar: None,
+            response_schema: None,
+            logprobs: None,
+            n: 1,
+            beam_width: 0,
+            length_penalty: 1.0,
+            all_beams: false,
+            draft_tokens: 4,
+            lora_adapters: None,
             token_callback: None,
+            logprobs_callback: None,
+            sampler: None,
             path_prompt_cache: String::from(""),
             m_lock: false,
             m_map: false,
@@ -116,6 +294,18 @@ impl ModelOptions {
         self.context_size = context_size;
     }
 
+    pub fn set_draft_model(&mut self, draft_model: String) {
+        self.draft_model = Some(draft_model);
+    }
+
+    pub fn add_lora_adapter(&mut self, path: String, scale: f32) {
+        self.lora_adapters.push(LoraAdapter::new(path, scale));
+    }
+
+    pub fn set_lora_base(&mut self, lora_base: String) {
+        self.lora_base = lora_base;
+    }
+
     pub fn set_model_seed(&mut self, seed: i32) {
         self.seed = seed;
     }
@@ -154,6 +344,11 @@ impl ModelOptions {
 }
 
 impl PredictOptions {
+    /// A copy of the settings, without the callbacks and the custom sampler.
+    pub fn copy_settings(&self) -> Result<Self, LlamaError> {
+        Ok(serde_json::from_value(serde_json::to_value(self)?)?)
+    }
+
     pub fn set_prediction_tensor_split(&mut self, tensor_split: String) {
         self.tensor_split = tensor_split;
     }
@@ -194,6 +389,14 @@ impl PredictOptions {
         self.token_callback = token_callback;
     }
 
+    pub fn set_logprobs_callback(&mut self, logprobs_callback: Option<LogprobsCallback>) {
+        self.logprobs_callback = logprobs_callback;
+    }
+
+    pub fn set_sampler(&mut self, sampler: SamplerChain) {
+        self.sampler = Some(sampler);
+    }
+
     pub fn set_path_prompt_cache(&mut self, path_prompt_cache: String) {
         self.path_prompt_cache = path_prompt_cache;
     }
@@ -246,6 +449,38 @@ impl PredictOptions {
         self.typical_p = typical_p;
     }
 
+    pub fn add_stop_pattern(&mut self, pattern: String) {
+        self.stop_patterns.push(pattern);
+    }
+
+    pub fn set_max_duration(&mut self, max_duration: Duration) {
+        self.max_duration = Some(max_duration);
+    }
+
+    pub fn set_max_newlines(&mut self, max_newlines: usize) {
+        self.max_newlines = Some(max_newlines);
+    }
+
+    pub fn set_loop_detection(&mut self, loop_detection: LoopDetection) {
+        self.loop_detection = Some(loop_detection);
+    }
+
+    pub fn set_min_p(&mut self, min_p: f32) {
+        self.min_p = min_p;
+    }
+
+    pub fn set_dynamic_temperature(&mut self, dynamic_temperature: DynamicTemperature) {
+        self.dynamic_temperature = Some(dynamic_temperature);
+    }
+
+    pub fn set_dry(&mut self, dry: DryOptions) {
+        self.dry = Some(dry);
+    }
+
+    pub fn set_xtc(&mut self, xtc: XtcOptions) {
+        self.xtc = Some(xtc);
+    }
+
     pub fn set_frequency_penalty(&mut self, frequency_penalty: f32) {
         self.frequency_penalty = frequency_penalty;
     }
@@ -270,11 +505,233 @@ impl PredictOptions {
         self.penalize_nl = true;
     }
 
-    pub fn set_logit_bias(&mut self, logit_bias: String) {
-        self.logit_bias = logit_bias;
+    pub fn set_logit_bias(&mut self, token: i32, bias: f32) {
+        self.logit_bias.insert(token, bias);
+    }
+
+    pub fn ban_token(&mut self, token: i32) {
+        self.logit_bias.insert(token, f32::NEG_INFINITY);
+    }
+
+    pub fn set_grammar(&mut self, grammar: String) {
+        self.grammar = Some(grammar);
+    }
+
+    pub fn set_response_schema(&mut self, schema: Value) {
+        self.response_schema = Some(schema);
+    }
+
+    pub fn set_logprobs(&mut self, top_n: usize) {
+        self.logprobs = Some(top_n);
+    }
+
+    pub fn set_n(&mut self, n: usize) {
+        self.n = n;
+    }
+
+    pub fn set_beam_width(&mut self, beam_width: usize) {
+        self.beam_width = beam_width;
+    }
+
+    pub fn set_length_penalty(&mut self, length_penalty: f32) {
+        self.length_penalty = length_penalty;
+    }
+
+    pub fn enable_all_beams(&mut self) {
+        self.all_beams = true;
+    }
+
+    pub fn set_draft_tokens(&mut self, draft_tokens: usize) {
+        self.draft_tokens = draft_tokens;
+    }
+
+    pub fn set_lora_adapters(&mut self, lora_adapters: Vec<LoraAdapter>) {
+        self.lora_adapters = Some(lora_adapters);
     }
 
     pub fn ignore_eos(&mut self) {
         self.ignore_eos = true;
     }
+
+    /// Whether the samplers that only exist on the Rust side are enabled, which
+    /// replaces the built-in chain with `SamplerChain::from_options`.
+    pub fn uses_extended_samplers(&self) -> bool {
+        self.min_p > 0.0
+            || self.dynamic_temperature.is_some()
+            || self.dry.is_some()
+            || self.xtc.is_some()
+    }
+
+    pub fn validate(&self) -> Result<(), LlamaError> {
+        if !(0.0..=1.0).contains(&self.min_p) {
+            return Err(LlamaError::InvalidOptions(format!(
+                "min_p must be between 0 and 1, got {}",
+                self.min_p
+            )));
+        }
+        if let Some(dynatemp) = &self.dynamic_temperature {
+            if dynatemp.range < 0.0 {
+                return Err(LlamaError::InvalidOptions(format!(
+                    "dynamic temperature range can't be negative, got {}",
+                    dynatemp.range
+                )));
+            }
+            if dynatemp.exponent <= 0.0 {
+                return Err(LlamaError::InvalidOptions(format!(
+                    "dynamic temperature exponent must be positive, got {}",
+                    dynatemp.exponent
+                )));
+            }
+        }
+        if let Some(dry) = &self.dry {
+            if dry.multiplier < 0.0 {
+                return Err(LlamaError::InvalidOptions(format!(
+                    "DRY multiplier can't be negative, got {}",
+                    dry.multiplier
+                )));
+            }
+            if dry.base < 1.0 {
+                return Err(LlamaError::InvalidOptions(format!(
+                    "DRY base must be at least 1, got {}",
+                    dry.base
+                )));
+            }
+            if dry.allowed_length == 0 {
+                return Err(LlamaError::InvalidOptions(
+                    "DRY allowed_length must be at least 1".to_string(),
+                ));
+            }
+            if dry.sequence_breakers.iter().any(|b| b.is_empty()) {
+                return Err(LlamaError::InvalidOptions(
+                    "DRY sequence breakers can't be empty".to_string(),
+                ));
+            }
+        }
+        if let Some(xtc) = &self.xtc {
+            if !(0.0..=1.0).contains(&xtc.probability) {
+                return Err(LlamaError::InvalidOptions(format!(
+                    "XTC probability must be between 0 and 1, got {}",
+                    xtc.probability
+                )));
+            }
+            if !(0.0..=1.0).contains(&xtc.threshold) {
+                return Err(LlamaError::InvalidOptions(format!(
+                    "XTC threshold must be between 0 and 1, got {}",
+                    xtc.threshold
+                )));
+            }
+        }
+        if let Some(detection) = &self.loop_detection {
+            if detection.max_ngram == 0 || detection.ngram_repeats < 2 || detection.line_repeats < 2
+            {
+                return Err(LlamaError::InvalidOptions(
+                    "loop detection needs max_ngram of at least 1 and repeats of at least 2"
+                        .to_string(),
+                ));
+            }
+        }
+        if self.n == 0 {
+            return Err(LlamaError::InvalidOptions(
+                "n must be at least 1".to_string(),
+            ));
+        }
+        if self.n > 1 && (self.sampler.is_some() || self.logprobs_callback.is_some()) {
+            return Err(LlamaError::InvalidOptions(
+                "a custom sampler or logprobs callback can't be used with n > 1".to_string(),
+            ));
+        }
+        if self.beam_width > 1 {
+            if self.grammar.is_some() || self.response_schema.is_some() || self.sampler.is_some() {
+                return Err(LlamaError::InvalidOptions(
+                    "beam search can't be used with a grammar, response_schema or custom sampler"
+                        .to_string(),
+                ));
+            }
+            if self.n > 1 {
+                return Err(LlamaError::InvalidOptions(
+                    "beam search returns one completion, n must be 1".to_string(),
+                ));
+            }
+            // beams aren't sampled, so there are no logprobs to report
+            if self.logprobs.is_some() || self.logprobs_callback.is_some() {
+                return Err(LlamaError::InvalidOptions(
+                    "beam search can't be used with logprobs or a logprobs callback".to_string(),
+                ));
+            }
+            // the search only stops at end of text or the token limit
+            if !self.stop_prompts.is_empty()
+                || !self.stop_patterns.is_empty()
+                || self.max_duration.is_some()
+                || self.max_newlines.is_some()
+                || self.loop_detection.is_some()
+            {
+                return Err(LlamaError::InvalidOptions(
+                    "beam search can't be used with stop prompts, stop patterns, max_duration, max_newlines or loop detection"
+                        .to_string(),
+                ));
+            }
+            if !self.length_penalty.is_finite() {
+                return Err(LlamaError::InvalidOptions(format!(
+                    "length_penalty must be finite, got {}",
+                    self.length_penalty
+                )));
+            }
+        }
+        if self.draft_tokens > MAX_DRAFT_TOKENS {
+            return Err(LlamaError::InvalidOptions(format!(
+                "draft_tokens can't be above {}, got {}",
+                MAX_DRAFT_TOKENS, self.draft_tokens
+            )));
+        }
+        if self.mirostat != 0 && self.uses_extended_samplers() {
+            return Err(LlamaError::InvalidOptions(
+                "mirostat can't be combined with min_p, dynamic temperature, DRY or XTC"
+                    .to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// JSON has no infinity, so bans are written as the string "-inf".
+mod logit_bias_format {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Bias {
+        Number(f32),
+        Text(String),
+    }
+
+    pub fn serialize<S: Serializer>(
+        logit_bias: &HashMap<i32, f32>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(logit_bias.iter().map(|(token, &bias)| {
+            if bias.is_finite() {
+                (token, Bias::Number(bias))
+            } else {
+                (token, Bias::Text(bias.to_string()))
+            }
+        }))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<i32, f32>, D::Error> {
+        HashMap::<i32, Bias>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(token, bias)| match bias {
+                Bias::Number(bias) => Ok((token, bias)),
+                Bias::Text(text) => text
+                    .parse()
+                    .map(|bias| (token, bias))
+                    .map_err(serde::de::Error::custom),
+            })
+            .collect()
+    }
 }
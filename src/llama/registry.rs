@@ -0,0 +1,203 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use super::{
+    error::LlamaError,
+    manager::{ModelGuard, ModelSlot, ModelState},
+    memory::MemoryEstimate,
+    options::ModelOptions,
+};
+use crate::{config::ModelConfig, LOGGER};
+
+// How often a load waiting for memory checks whether requests on unloaded
+// models finished.
+const RETIRED_POLL: Duration = Duration::from_millis(100);
+
+/// An alias of the registry and the state of its model.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelStatus {
+    pub alias: String,
+    pub path: String,
+    pub state: ModelState,
+    /// Fraction of the model loaded, while it's loading.
+    pub progress: Option<f32>,
+}
+
+/// The models configured under `[models.<alias>]`, loaded on first use. When
+/// loading one would take the loaded models over `memory_limit`, the least
+/// recently used ones are unloaded first.
+pub struct ModelRegistry {
+    models: HashMap<String, ModelSlot>,
+    memory_limit: Option<u64>,
+    // Held while making room for a model and loading it.
+    loading: Mutex<()>,
+}
+
+impl ModelRegistry {
+    pub fn new(models: HashMap<String, ModelConfig>, memory_limit: Option<u64>) -> Self {
+        Self {
+            models: models
+                .into_iter()
+                .map(|(alias, config)| (alias, ModelSlot::new(config.path, config.options)))
+                .collect(),
+            memory_limit,
+            loading: Mutex::new(()),
+        }
+    }
+
+    /// The slot of the model configured as `alias`.
+    pub fn slot(&self, alias: &str) -> Result<&ModelSlot, LlamaError> {
+        self.models
+            .get(alias)
+            .ok_or_else(|| LlamaError::UnknownModel(alias.to_string()))
+    }
+
+    /// The model configured as `alias`, loading it if needed.
+    pub async fn get(&self, alias: &str) -> Result<ModelGuard, LlamaError> {
+        let slot = self.slot(alias)?;
+        if let Some(llama) = slot.loaded().await {
+            return Ok(llama);
+        }
+
+        let _loading = self.loading.lock().await;
+        // another request may have loaded it meanwhile
+        if let Some(llama) = slot.loaded().await {
+            return Ok(llama);
+        }
+        if let Some(limit) = self.memory_limit {
+            self.make_room(alias, slot, limit).await?;
+        }
+
+        slot.get().await
+    }
+
+    // Unloads the least recently used models until `slot` fits under `limit`.
+    // Models unloaded while requests still use them count until those finish,
+    // so when only those are left this waits for the requests.
+    async fn make_room(&self, alias: &str, slot: &ModelSlot, limit: u64) -> Result<(), LlamaError> {
+        let size = estimate_size(slot.path(), slot.options());
+        if size > limit {
+            return Err(LlamaError::MemoryLimit {
+                path: slot.path().to_string(),
+                needed: size,
+                limit,
+            });
+        }
+
+        let mut sizes = HashMap::new();
+        let mut waiting = false;
+        loop {
+            let mut used = 0;
+            let mut loaded = vec![];
+            for (other_alias, other) in &self.models {
+                let copies =
+                    other.retired_copies() + (other.state() == ModelState::Loaded) as usize;
+                if copies == 0 {
+                    continue;
+                }
+                let other_size = *sizes
+                    .entry(other_alias)
+                    .or_insert_with(|| estimate_size(other.path(), other.options()));
+                used += other_size * copies as u64;
+                if other.state() == ModelState::Loaded {
+                    loaded.push((other, other_size));
+                }
+            }
+            if used + size <= limit {
+                return Ok(());
+            }
+
+            match loaded
+                .into_iter()
+                .min_by_key(|(other, _)| other.last_used())
+            {
+                // requests still using it keep it alive until they finish
+                Some((lru, _)) => {
+                    lru.unload(&format!("making room for {}", alias)).await;
+                }
+                None => {
+                    if !waiting {
+                        slog::info!(
+                            LOGGER,
+                            "waiting for requests on unloaded models to finish before loading {}",
+                            alias
+                        );
+                        waiting = true;
+                    }
+                    tokio::time::sleep(RETIRED_POLL).await;
+                }
+            }
+        }
+    }
+
+    /// Unloads the models no request has used for `timeout`.
+    pub async fn unload_idle(&self, timeout: Duration) {
+        for slot in self.models.values() {
+            slot.unload_if_idle(timeout).await;
+        }
+    }
+
+    /// Every alias sorted by name.
+    pub fn list(&self) -> Vec<ModelStatus> {
+        let mut models: Vec<ModelStatus> = self
+            .models
+            .iter()
+            .map(|(alias, slot)| ModelStatus {
+                alias: alias.clone(),
+                path: slot.path().to_string(),
+                state: slot.state(),
+                progress: slot.loading_progress(),
+            })
+            .collect();
+        models.sort_by(|a, b| a.alias.cmp(&b.alias));
+        models
+    }
+}
+
+// Memory of the model and of its draft model.
+fn estimate_size(path: &str, opts: &ModelOptions) -> u64 {
+    let estimate =
+        |path: &str| MemoryEstimate::for_model_or_file_size(path, opts.context_size).total();
+    estimate(path) + opts.draft_model.as_deref().map_or(0, estimate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(memory_limit: Option<u64>) -> ModelRegistry {
+        let config = ModelConfig {
+            path: format!("{}/tests/fixtures/valid.gguf", env!("CARGO_MANIFEST_DIR")),
+            options: ModelOptions::default(),
+        };
+        ModelRegistry::new(HashMap::from([("tiny".to_string(), config)]), memory_limit)
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_aliases() {
+        let Err(err) = registry(None).get("huge").await else {
+            panic!("loaded an unknown alias");
+        };
+        assert!(matches!(err, LlamaError::UnknownModel(alias) if alias == "huge"));
+    }
+
+    #[tokio::test]
+    async fn rejects_models_over_the_limit() {
+        let Err(err) = registry(Some(1)).get("tiny").await else {
+            panic!("loaded a model over the limit");
+        };
+        assert!(matches!(err, LlamaError::MemoryLimit { limit: 1, .. }));
+    }
+
+    #[tokio::test]
+    async fn unloaded_slots_hold_nothing() {
+        let registry = registry(None);
+        let slot = registry.slot("tiny").unwrap();
+        assert!(slot.loaded().await.is_none());
+        assert_eq!(slot.retired_copies(), 0);
+        assert!(!slot.unload("test").await);
+        assert_eq!(registry.list()[0].state, ModelState::Unloaded);
+    }
+}
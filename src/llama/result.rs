@@ -0,0 +1,84 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{beam::Beam, logprobs::TokenLogprobs};
+
+/// Why generation stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The token limit was reached.
+    Length,
+    /// The model produced end of text.
+    Eos,
+    /// A stop prompt was generated.
+    Stop,
+    /// The token callback asked to stop.
+    Cancelled,
+    /// The output matched one of the stop patterns.
+    StopPattern,
+    /// Generation ran longer than the maximum duration.
+    Timeout,
+    /// The output reached the maximum number of newlines.
+    Newlines,
+    /// The output started repeating itself.
+    Repetition,
+}
+
+impl fmt::Display for FinishReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FinishReason::Length => "length",
+            FinishReason::Eos => "eos",
+            FinishReason::Stop => "stop",
+            FinishReason::Cancelled => "cancelled",
+            FinishReason::StopPattern => "stop_pattern",
+            FinishReason::Timeout => "timeout",
+            FinishReason::Newlines => "newlines",
+            FinishReason::Repetition => "repetition",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PredictResult {
+    pub text: String,
+    pub finish_reason: FinishReason,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Prompt tokens reused from the context instead of being evaluated.
+    pub cached_tokens: usize,
+    pub prompt_eval_ms: f64,
+    pub gen_ms: f64,
+    pub tokens_per_sec: f64,
+    /// Set when `PredictOptions::logprobs` is.
+    pub logprobs: Option<Vec<TokenLogprobs>>,
+    /// The parsed output, set when `PredictOptions::response_schema` is and the
+    /// output is valid JSON.
+    pub json: Option<Value>,
+    /// Set by beam search: the best beam, followed by the others when
+    /// `PredictOptions::all_beams` is set.
+    pub beams: Option<Vec<Beam>>,
+    /// Set when the draft model was used.
+    pub speculative: Option<SpeculativeStats>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SpeculativeStats {
+    /// Tokens proposed by the draft model.
+    pub drafted: usize,
+    /// Proposed tokens the model agreed with.
+    pub accepted: usize,
+}
+
+impl SpeculativeStats {
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.drafted == 0 {
+            0.0
+        } else {
+            self.accepted as f64 / self.drafted as f64
+        }
+    }
+}
@@ -0,0 +1,811 @@
+use std::collections::{HashMap, HashSet};
+
+use rand::{distributions::WeightedIndex, prelude::Distribution, rngs::StdRng, Rng, SeedableRng};
+
+use super::options::{DryOptions, DynamicTemperature, PredictOptions, XtcOptions};
+
+/// Mirrors llama.cpp's `llama_token_data`, candidates are handed over as is.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+    pub id: i32,
+    pub logit: f32,
+    pub p: f32,
+}
+
+/// The candidate tokens of one sampling step. Stages may reorder, rescale or
+/// drop candidates, `p` is only meaningful after `softmax`.
+#[derive(Debug, Clone)]
+pub struct Candidates {
+    pub data: Vec<Candidate>,
+    pub sorted: bool,
+}
+
+impl Candidates {
+    pub fn new(data: Vec<Candidate>) -> Self {
+        Self {
+            data,
+            sorted: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn sort_by_logit(&mut self) {
+        if !self.sorted {
+            self.data.sort_by(|a, b| {
+                b.logit
+                    .partial_cmp(&a.logit)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+            self.sorted = true;
+        }
+    }
+
+    /// Sorts by logit and sets `p` to the softmax of the logits.
+    pub fn softmax(&mut self) {
+        self.sort_by_logit();
+        let Some(max_logit) = self.data.first().map(|c| c.logit) else {
+            return;
+        };
+
+        let mut sum = 0.0;
+        for c in &mut self.data {
+            c.p = (c.logit - max_logit).exp();
+            sum += c.p;
+        }
+        for c in &mut self.data {
+            c.p /= sum;
+        }
+    }
+
+    /// Keeps the first `len` candidates, but never fewer than one.
+    pub fn truncate(&mut self, len: usize) {
+        self.data.truncate(len.max(1));
+    }
+}
+
+/// One stage of a sampler chain. `history` holds the most recent tokens of the
+/// context, oldest first.
+pub trait Sampler: Send {
+    fn apply(&mut self, candidates: &mut Candidates, history: &[i32]);
+
+    /// Name of the stage in a chain, its type name without the path by default.
+    fn name(&self) -> &'static str {
+        let name = std::any::type_name::<Self>();
+        let name = name.split('<').next().unwrap_or(name);
+        name.rsplit("::").next().unwrap_or(name)
+    }
+}
+
+impl<F> Sampler for F
+where
+    F: FnMut(&mut Candidates, &[i32]) + Send,
+{
+    fn apply(&mut self, candidates: &mut Candidates, history: &[i32]) {
+        self(candidates, history)
+    }
+}
+
+pub struct RepetitionPenalty {
+    pub last_n: usize,
+    pub repeat: f32,
+    pub frequency: f32,
+    pub presence: f32,
+    /// Tokens that are never penalized.
+    pub exempt: Vec<i32>,
+}
+
+impl Sampler for RepetitionPenalty {
+    fn apply(&mut self, candidates: &mut Candidates, history: &[i32]) {
+        if self.last_n == 0 || (self.repeat == 1.0 && self.frequency == 0.0 && self.presence == 0.0)
+        {
+            return;
+        }
+
+        let mut counts = HashMap::new();
+        for &token in &history[history.len().saturating_sub(self.last_n)..] {
+            *counts.entry(token).or_insert(0usize) += 1;
+        }
+
+        for c in &mut candidates.data {
+            let Some(&count) = counts.get(&c.id) else {
+                continue;
+            };
+            if self.exempt.contains(&c.id) {
+                continue;
+            }
+            if c.logit <= 0.0 {
+                c.logit *= self.repeat;
+            } else {
+                c.logit /= self.repeat;
+            }
+            c.logit -= count as f32 * self.frequency + self.presence;
+        }
+        candidates.sorted = false;
+    }
+}
+
+pub struct TopK(pub usize);
+
+impl Sampler for TopK {
+    fn apply(&mut self, candidates: &mut Candidates, _history: &[i32]) {
+        if self.0 == 0 {
+            return;
+        }
+        candidates.sort_by_logit();
+        candidates.truncate(self.0);
+    }
+}
+
+pub struct TopP(pub f32);
+
+impl Sampler for TopP {
+    fn apply(&mut self, candidates: &mut Candidates, _history: &[i32]) {
+        if self.0 >= 1.0 {
+            return;
+        }
+        candidates.softmax();
+
+        let mut cum = 0.0;
+        let mut keep = candidates.len();
+        for (i, c) in candidates.data.iter().enumerate() {
+            cum += c.p;
+            if cum >= self.0 {
+                keep = i + 1;
+                break;
+            }
+        }
+        candidates.truncate(keep);
+    }
+}
+
+pub struct TailFree(pub f32);
+
+impl Sampler for TailFree {
+    fn apply(&mut self, candidates: &mut Candidates, _history: &[i32]) {
+        if self.0 >= 1.0 || candidates.len() <= 2 {
+            return;
+        }
+        candidates.softmax();
+
+        let first: Vec<f32> = candidates
+            .data
+            .windows(2)
+            .map(|w| w[0].p - w[1].p)
+            .collect();
+        let mut second: Vec<f32> = first.windows(2).map(|w| (w[0] - w[1]).abs()).collect();
+        let sum: f32 = second.iter().sum();
+        if sum > 1e-6 {
+            for d in &mut second {
+                *d /= sum;
+            }
+        } else {
+            let n = second.len() as f32;
+            second.iter_mut().for_each(|d| *d = 1.0 / n);
+        }
+
+        let mut cum = 0.0;
+        let mut keep = candidates.len();
+        for (i, d) in second.iter().enumerate() {
+            cum += d;
+            if cum > self.0 {
+                keep = i;
+                break;
+            }
+        }
+        candidates.truncate(keep);
+    }
+}
+
+pub struct Typical(pub f32);
+
+impl Sampler for Typical {
+    fn apply(&mut self, candidates: &mut Candidates, _history: &[i32]) {
+        if self.0 >= 1.0 {
+            return;
+        }
+        candidates.softmax();
+
+        let entropy: f32 = candidates
+            .data
+            .iter()
+            .filter(|c| c.p > 0.0)
+            .map(|c| -c.p * c.p.ln())
+            .sum();
+        let mut shifted: Vec<(f32, Candidate)> = candidates
+            .data
+            .iter()
+            .map(|&c| ((-c.p.ln() - entropy).abs(), c))
+            .collect();
+        shifted.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+
+        let mut cum = 0.0;
+        let mut keep = shifted.len();
+        for (i, (_, c)) in shifted.iter().enumerate() {
+            cum += c.p;
+            if cum > self.0 {
+                keep = i + 1;
+                break;
+            }
+        }
+        candidates.data = shifted
+            .into_iter()
+            .take(keep.max(1))
+            .map(|(_, c)| c)
+            .collect();
+        candidates.sorted = false;
+    }
+}
+
+pub struct Temperature(pub f32);
+
+impl Sampler for Temperature {
+    fn apply(&mut self, candidates: &mut Candidates, _history: &[i32]) {
+        for c in &mut candidates.data {
+            c.logit /= self.0;
+        }
+    }
+}
+
+pub struct MinP(pub f32);
+
+impl Sampler for MinP {
+    fn apply(&mut self, candidates: &mut Candidates, _history: &[i32]) {
+        if self.0 <= 0.0 {
+            return;
+        }
+        candidates.softmax();
+
+        let min = candidates.data.first().map_or(0.0, |c| c.p) * self.0;
+        let keep = candidates.data.iter().take_while(|c| c.p >= min).count();
+        candidates.truncate(keep);
+    }
+}
+
+pub struct EntropyTemperature {
+    pub temperature: f32,
+    pub range: f32,
+    pub exponent: f32,
+}
+
+impl EntropyTemperature {
+    pub fn new(temperature: f32, opts: &DynamicTemperature) -> Self {
+        Self {
+            temperature,
+            range: opts.range,
+            exponent: opts.exponent,
+        }
+    }
+}
+
+impl Sampler for EntropyTemperature {
+    fn apply(&mut self, candidates: &mut Candidates, history: &[i32]) {
+        if candidates.len() <= 1 {
+            return;
+        }
+        candidates.softmax();
+
+        let min_temp = (self.temperature - self.range).max(0.0);
+        let max_temp = self.temperature + self.range;
+        let entropy: f32 = candidates
+            .data
+            .iter()
+            .filter(|c| c.p > 0.0)
+            .map(|c| -c.p * c.p.ln())
+            .sum();
+        let normalized = entropy / (candidates.len() as f32).ln();
+        let temp = min_temp + (max_temp - min_temp) * normalized.powf(self.exponent);
+
+        if temp <= 0.0 {
+            candidates.truncate(1);
+        } else {
+            Temperature(temp).apply(candidates, history);
+        }
+    }
+}
+
+pub struct Dry {
+    pub multiplier: f32,
+    pub base: f32,
+    pub allowed_length: usize,
+    pub last_n: usize,
+    /// Tokens that end a repeated sequence, see `LLama::tokens_containing`.
+    pub breakers: HashSet<i32>,
+}
+
+// Repeats longer than this are penalized as if they were this long.
+const DRY_MAX_MATCH: usize = 64;
+
+impl Dry {
+    pub fn new(opts: &DryOptions, breakers: HashSet<i32>) -> Self {
+        Self {
+            multiplier: opts.multiplier,
+            base: opts.base,
+            allowed_length: opts.allowed_length,
+            last_n: opts.last_n,
+            breakers,
+        }
+    }
+}
+
+impl Sampler for Dry {
+    fn apply(&mut self, candidates: &mut Candidates, history: &[i32]) {
+        if self.multiplier == 0.0 {
+            return;
+        }
+        let history = match self.last_n {
+            0 => history,
+            n => &history[history.len().saturating_sub(n)..],
+        };
+        let Some((&last, _)) = history.split_last() else {
+            return;
+        };
+        if self.breakers.contains(&last) {
+            return;
+        }
+
+        // for every earlier occurrence of the last token, the token that followed it
+        // would continue a repeat as long as the match that ends there
+        let end = history.len() - 1;
+        let mut match_lengths: HashMap<i32, usize> = HashMap::new();
+        for i in (0..end).filter(|&i| history[i] == last) {
+            let next = history[i + 1];
+            if self.breakers.contains(&next) {
+                continue;
+            }
+
+            let mut len = 1;
+            while len < DRY_MAX_MATCH && len <= i {
+                let token = history[i - len];
+                if token != history[end - len] || self.breakers.contains(&token) {
+                    break;
+                }
+                len += 1;
+            }
+
+            let longest = match_lengths.entry(next).or_insert(0);
+            *longest = (*longest).max(len);
+        }
+
+        for c in &mut candidates.data {
+            if let Some(&len) = match_lengths.get(&c.id) {
+                if len >= self.allowed_length {
+                    c.logit -= self.multiplier * self.base.powi((len - self.allowed_length) as i32);
+                }
+            }
+        }
+        candidates.sorted = false;
+    }
+}
+
+pub struct Xtc {
+    pub probability: f32,
+    pub threshold: f32,
+    rng: StdRng,
+}
+
+impl Xtc {
+    pub fn new(opts: &XtcOptions, seed: u64) -> Self {
+        Self {
+            probability: opts.probability,
+            threshold: opts.threshold,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl Sampler for Xtc {
+    fn apply(&mut self, candidates: &mut Candidates, _history: &[i32]) {
+        if self.probability <= 0.0 || self.rng.gen::<f32>() >= self.probability {
+            return;
+        }
+        candidates.softmax();
+
+        // keep the least likely of the tokens above the threshold
+        let above = candidates
+            .data
+            .iter()
+            .take_while(|c| c.p >= self.threshold)
+            .count();
+        if above >= 2 {
+            candidates.data.drain(..above - 1);
+        }
+    }
+}
+
+/// How the token is picked once all stages have run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    Greedy,
+    Distribution,
+}
+
+/// An ordered list of sampler stages followed by a selection. Replaces the
+/// fixed sampling chain of `llama_predict` when set on `PredictOptions`.
+pub struct SamplerChain {
+    stages: Vec<Box<dyn Sampler>>,
+    selection: Selection,
+    rng: StdRng,
+}
+
+impl SamplerChain {
+    pub fn new(selection: Selection, seed: u64) -> Self {
+        Self {
+            stages: vec![],
+            selection,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// The chain `llama_predict` runs by default without mirostat, extended with
+    /// the samplers that only exist here. `token_nl` is exempt from repetition
+    /// penalties unless `opts.penalize_nl` is set, `dry_breakers` are the tokens
+    /// matching `opts.dry`'s sequence breakers. The same positive `opts.seed`
+    /// always yields the same choices.
+    /// `n_ctx` is the context size, which a negative `opts.repeat` penalizes
+    /// over.
+    pub fn from_options(
+        opts: &PredictOptions,
+        token_nl: i32,
+        dry_breakers: HashSet<i32>,
+        n_ctx: usize,
+    ) -> Self {
+        let seed = if opts.seed <= 0 {
+            rand::random()
+        } else {
+            opts.seed as u64
+        };
+
+        let penalty = RepetitionPenalty {
+            last_n: repeat_last_n(opts.repeat, n_ctx),
+            repeat: opts.penalty,
+            frequency: opts.frequency_penalty,
+            presence: opts.presence_penalty,
+            exempt: if opts.penalize_nl {
+                vec![]
+            } else {
+                vec![token_nl]
+            },
+        };
+        let selection = if opts.temperature <= 0.0 {
+            Selection::Greedy
+        } else {
+            Selection::Distribution
+        };
+        let mut chain = Self::new(selection, seed).with(penalty);
+        if let Some(dry) = &opts.dry {
+            chain.push(Dry::new(dry, dry_breakers));
+        }
+        if selection == Selection::Greedy {
+            return chain;
+        }
+
+        chain.push(TopK(opts.top_k.max(0) as usize));
+        chain.push(TailFree(opts.tail_free_sampling_z));
+        chain.push(Typical(opts.typical_p));
+        chain.push(TopP(opts.top_p));
+        chain.push(MinP(opts.min_p));
+        if let Some(xtc) = &opts.xtc {
+            // XTC draws from its own generator so adding stages does not shift it
+            chain.push(Xtc::new(xtc, seed.wrapping_add(1)));
+        }
+        match &opts.dynamic_temperature {
+            Some(dynatemp) => chain.push(EntropyTemperature::new(opts.temperature, dynatemp)),
+            None => chain.push(Temperature(opts.temperature)),
+        }
+
+        chain
+    }
+
+    pub fn with(mut self, stage: impl Sampler + 'static) -> Self {
+        self.push(stage);
+        self
+    }
+
+    pub fn push(&mut self, stage: impl Sampler + 'static) {
+        self.stages.push(Box::new(stage));
+    }
+
+    pub fn insert(&mut self, index: usize, stage: impl Sampler + 'static) {
+        self.stages.insert(index, Box::new(stage));
+    }
+
+    /// Removes and returns the stage at `index`.
+    ///
+    /// # Panics
+    ///
+    /// If `index` is out of bounds.
+    pub fn remove(&mut self, index: usize) -> Box<dyn Sampler> {
+        self.stages.remove(index)
+    }
+
+    /// Moves the stage at `from` to `to`, shifting the stages in between.
+    ///
+    /// # Panics
+    ///
+    /// If either index is out of bounds.
+    pub fn move_stage(&mut self, from: usize, to: usize) {
+        let stage = self.stages.remove(from);
+        self.stages.insert(to, stage);
+    }
+
+    /// Index of the first stage named `name`, see `Sampler::name`.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.stages.iter().position(|stage| stage.name() == name)
+    }
+
+    /// Names of the stages in the order they run.
+    pub fn names(&self) -> Vec<&'static str> {
+        self.stages.iter().map(|stage| stage.name()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.stages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Runs every stage and picks a token, `None` if no candidate is left.
+    pub fn sample(&mut self, candidates: &mut Candidates, history: &[i32]) -> Option<i32> {
+        for stage in &mut self.stages {
+            stage.apply(candidates, history);
+        }
+
+        match self.selection {
+            Selection::Greedy => {
+                candidates.sort_by_logit();
+                candidates.data.first().map(|c| c.id)
+            }
+            Selection::Distribution => {
+                candidates.softmax();
+                let dist = WeightedIndex::new(candidates.data.iter().map(|c| c.p)).ok()?;
+                Some(candidates.data[dist.sample(&mut self.rng)].id)
+            }
+        }
+    }
+}
+
+/// Tokens the repetition penalty looks back over for `repeat`, where a negative
+/// value means the whole context as in llama_predict.
+pub(crate) fn repeat_last_n(repeat: i32, n_ctx: usize) -> usize {
+    if repeat < 0 {
+        n_ctx
+    } else {
+        repeat as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates(logits: &[f32]) -> Candidates {
+        Candidates::new(
+            logits
+                .iter()
+                .enumerate()
+                .map(|(id, &logit)| Candidate {
+                    id: id as i32,
+                    logit,
+                    p: 0.0,
+                })
+                .collect(),
+        )
+    }
+
+    fn chain() -> SamplerChain {
+        SamplerChain::new(Selection::Greedy, 1)
+            .with(TopK(2))
+            .with(MinP(0.1))
+            .with(Temperature(0.5))
+    }
+
+    #[test]
+    fn stages_are_named_after_their_type() {
+        assert_eq!(chain().names(), ["TopK", "MinP", "Temperature"]);
+        assert_eq!(chain().position("MinP"), Some(1));
+        assert_eq!(chain().position("TopP"), None);
+    }
+
+    #[test]
+    fn removes_and_reorders_stages() {
+        let mut chain = chain();
+        assert_eq!(chain.remove(1).name(), "MinP");
+        assert_eq!(chain.names(), ["TopK", "Temperature"]);
+
+        chain.move_stage(1, 0);
+        assert_eq!(chain.names(), ["Temperature", "TopK"]);
+        chain.push(MinP(0.1));
+        chain.move_stage(0, 2);
+        assert_eq!(chain.names(), ["TopK", "MinP", "Temperature"]);
+    }
+
+    #[test]
+    fn greedy_picks_the_highest_logit_left() {
+        let mut chain = SamplerChain::new(Selection::Greedy, 1).with(
+            |candidates: &mut Candidates, _history: &[i32]| {
+                candidates.data.retain(|c| c.id != 2);
+            },
+        );
+        assert_eq!(
+            chain.sample(&mut candidates(&[1.0, 2.0, 3.0]), &[]),
+            Some(1)
+        );
+    }
+
+    // Candidates whose softmax is `probs`.
+    fn with_probs(probs: &[f32]) -> Candidates {
+        candidates(&probs.iter().map(|p| p.ln()).collect::<Vec<_>>())
+    }
+
+    fn ids(candidates: &Candidates) -> Vec<i32> {
+        candidates.data.iter().map(|c| c.id).collect()
+    }
+
+    fn logit(candidates: &Candidates, id: i32) -> f32 {
+        candidates.data.iter().find(|c| c.id == id).unwrap().logit
+    }
+
+    #[test]
+    fn min_p_keeps_candidates_near_the_top() {
+        let mut c = with_probs(&[0.05, 0.5, 0.15, 0.3]);
+        MinP(0.2).apply(&mut c, &[]);
+        assert_eq!(ids(&c), [1, 3, 2]);
+
+        let mut c = with_probs(&[0.05, 0.5, 0.15, 0.3]);
+        MinP(0.0).apply(&mut c, &[]);
+        assert_eq!(c.len(), 4);
+    }
+
+    #[test]
+    fn entropy_temperature_follows_the_entropy() {
+        let opts = DynamicTemperature {
+            range: 0.5,
+            exponent: 1.0,
+        };
+
+        // a uniform distribution has the highest entropy, so the highest temperature
+        let mut c = candidates(&[1.0, 1.0, 1.0, 1.0]);
+        EntropyTemperature::new(1.0, &opts).apply(&mut c, &[]);
+        assert!(c.data.iter().all(|c| (c.logit - 1.0 / 1.5).abs() < 1e-5));
+
+        // a nearly certain token gets a temperature near the lowest
+        let mut c = candidates(&[30.0, 0.0, 0.0]);
+        EntropyTemperature::new(1.0, &opts).apply(&mut c, &[]);
+        assert!((logit(&c, 0) - 60.0).abs() < 0.1);
+
+        // a certain token, with a lowest temperature of 0, is picked greedily
+        let mut c = candidates(&[0.0, f32::NEG_INFINITY, f32::NEG_INFINITY]);
+        EntropyTemperature::new(0.5, &opts).apply(&mut c, &[]);
+        assert_eq!(ids(&c), [0]);
+    }
+
+    fn dry(allowed_length: usize, breakers: &[i32]) -> Dry {
+        Dry::new(
+            &DryOptions {
+                multiplier: 1.0,
+                base: 2.0,
+                allowed_length,
+                last_n: 0,
+                sequence_breakers: vec![],
+            },
+            breakers.iter().copied().collect(),
+        )
+    }
+
+    #[test]
+    fn dry_penalizes_the_token_that_continues_a_repeat() {
+        // "1 2 3 ... 1 2" would repeat with 3, after a match of length 2
+        let history = [1, 2, 3, 4, 1, 2];
+        let mut c = candidates(&[0.0; 5]);
+        dry(2, &[]).apply(&mut c, &history);
+        assert_eq!(logit(&c, 3), -1.0);
+        assert!([0, 1, 2, 4].iter().all(|&id| logit(&c, id) == 0.0));
+
+        // the penalty grows with the length over the allowed one
+        let mut c = candidates(&[0.0; 5]);
+        dry(1, &[]).apply(&mut c, &history);
+        assert_eq!(logit(&c, 3), -2.0);
+
+        let mut c = candidates(&[0.0; 5]);
+        dry(3, &[]).apply(&mut c, &history);
+        assert_eq!(logit(&c, 3), 0.0);
+    }
+
+    #[test]
+    fn dry_sequence_breakers_end_matches() {
+        let history = [1, 2, 3, 4, 1, 2];
+        let mut c = candidates(&[0.0; 5]);
+        dry(2, &[1]).apply(&mut c, &history);
+        assert_eq!(logit(&c, 3), 0.0);
+
+        let mut c = candidates(&[0.0; 5]);
+        dry(1, &[2]).apply(&mut c, &history);
+        assert_eq!(logit(&c, 3), 0.0);
+    }
+
+    fn xtc(probability: f32, seed: u64) -> Xtc {
+        Xtc::new(
+            &XtcOptions {
+                probability,
+                threshold: 0.1,
+            },
+            seed,
+        )
+    }
+
+    #[test]
+    fn xtc_keeps_the_least_likely_token_above_the_threshold() {
+        let mut c = with_probs(&[0.5, 0.3, 0.15, 0.05]);
+        xtc(1.0, 1).apply(&mut c, &[]);
+        assert_eq!(ids(&c), [2, 3]);
+
+        let mut c = with_probs(&[0.5, 0.3, 0.15, 0.05]);
+        xtc(0.0, 1).apply(&mut c, &[]);
+        assert_eq!(c.len(), 4);
+
+        // with a single token above the threshold there is nothing to exclude
+        let mut c = with_probs(&[0.95, 0.05]);
+        xtc(1.0, 1).apply(&mut c, &[]);
+        assert_eq!(c.len(), 2);
+    }
+
+    #[test]
+    fn xtc_is_reproducible_with_a_seed() {
+        let runs = |seed| {
+            let mut xtc = xtc(0.5, seed);
+            (0..32)
+                .map(|_| {
+                    let mut c = with_probs(&[0.5, 0.3, 0.15, 0.05]);
+                    xtc.apply(&mut c, &[]);
+                    c.len()
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = runs(7);
+        assert_eq!(first, runs(7));
+        assert!(first.contains(&2) && first.contains(&4));
+    }
+
+    #[test]
+    fn distribution_selection_is_reproducible_with_a_seed() {
+        let picks = |seed| {
+            let mut chain = SamplerChain::new(Selection::Distribution, seed);
+            (0..32)
+                .map(|_| chain.sample(&mut candidates(&[1.0, 1.0, 1.0, 1.0]), &[]))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(picks(42), picks(42));
+        assert_ne!(picks(42), picks(43));
+    }
+
+    #[test]
+    fn negative_repeat_penalizes_the_whole_context() {
+        assert_eq!(repeat_last_n(-1, 2048), 2048);
+        assert_eq!(repeat_last_n(0, 2048), 0);
+        assert_eq!(repeat_last_n(64, 2048), 64);
+
+        let opts = PredictOptions {
+            temperature: 0.0,
+            repeat: -1,
+            penalty: 2.0,
+            ..Default::default()
+        };
+        let mut chain = SamplerChain::from_options(&opts, 9, HashSet::new(), 8);
+        // token 1 leads, but was generated at the start of the context
+        let history = [1, 2, 2, 2, 2, 2, 2, 2];
+        assert_eq!(
+            chain.sample(&mut candidates(&[1.5, 2.0]), &history),
+            Some(0)
+        );
+    }
+}
@@ -0,0 +1,451 @@
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use super::{error::LlamaError, options::PredictOptions, result::FinishReason};
+
+// Matches of a stop pattern are only looked for this far before the newest
+// piece, so each piece costs the same however long the output gets.
+const STOP_PATTERN_WINDOW: usize = 4096;
+
+/// Stops generation once the output starts repeating itself.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoopDetection {
+    /// Longest run of tokens checked for back-to-back repetition.
+    pub max_ngram: usize,
+    /// How many times in a row a run of tokens has to occur.
+    pub ngram_repeats: usize,
+    /// How many times in a row the same non-empty line has to occur.
+    pub line_repeats: usize,
+}
+
+impl Default for LoopDetection {
+    fn default() -> Self {
+        Self {
+            max_ngram: 8,
+            ngram_repeats: 5,
+            line_repeats: 3,
+        }
+    }
+}
+
+/// The stop conditions of one predict that llama.cpp doesn't know about,
+/// checked as each token is generated.
+pub(crate) struct StopConditions {
+    // Each pattern with the longest match it can have in bytes, if bounded.
+    patterns: Vec<(Regex, Option<usize>)>,
+    deadline: Option<Instant>,
+    max_newlines: Option<usize>,
+    loop_detection: Option<LoopDetection>,
+    text: String,
+    pieces: Vec<String>,
+    newlines: usize,
+    // Generated text before the token that stopped generation, and where in it
+    // the stop pattern starts.
+    kept_len: usize,
+    pattern_start: Option<usize>,
+    reason: Option<FinishReason>,
+}
+
+impl StopConditions {
+    /// `None` when `opts` sets none of these conditions.
+    pub(crate) fn new(opts: &PredictOptions) -> Result<Option<Self>, LlamaError> {
+        if opts.stop_patterns.is_empty()
+            && opts.max_duration.is_none()
+            && opts.max_newlines.is_none()
+            && opts.loop_detection.is_none()
+        {
+            return Ok(None);
+        }
+
+        let patterns = opts
+            .stop_patterns
+            .iter()
+            .map(|pattern| {
+                let regex = Regex::new(pattern).map_err(|err| {
+                    LlamaError::InvalidOptions(format!(
+                        "invalid stop pattern {:?}: {}",
+                        pattern, err
+                    ))
+                })?;
+                let max_len = regex_syntax::parse(pattern)
+                    .ok()
+                    .and_then(|hir| hir.properties().maximum_len());
+                Ok((regex, max_len))
+            })
+            .collect::<Result<_, LlamaError>>()?;
+
+        Ok(Some(Self {
+            patterns,
+            deadline: opts.max_duration.map(|d: Duration| Instant::now() + d),
+            max_newlines: opts.max_newlines,
+            loop_detection: opts.loop_detection.clone(),
+            text: String::new(),
+            pieces: vec![],
+            newlines: 0,
+            kept_len: 0,
+            pattern_start: None,
+            reason: None,
+        }))
+    }
+
+    /// Adds the next generated piece, returns false when generation has to stop.
+    pub(crate) fn check(&mut self, piece: &str) -> bool {
+        if self.reason.is_some() {
+            return false;
+        }
+
+        self.text.push_str(piece);
+        if self.loop_detection.is_some() {
+            self.pieces.push(piece.to_string());
+        }
+        self.reason = self.stop_reason(piece);
+        if self.reason.is_none() {
+            self.kept_len = self.text.len();
+        }
+
+        self.reason.is_none()
+    }
+
+    fn stop_reason(&mut self, piece: &str) -> Option<FinishReason> {
+        // earlier pieces didn't match, so a match has to end in this one
+        let piece_start = self.text.len() - piece.len();
+        for (pattern, max_len) in &self.patterns {
+            let reach = max_len.map_or(STOP_PATTERN_WINDOW, |len| len.min(STOP_PATTERN_WINDOW));
+            let mut start = piece_start.saturating_sub(reach);
+            while !self.text.is_char_boundary(start) {
+                start -= 1;
+            }
+            if let Some(m) = pattern.find_at(&self.text, start) {
+                self.pattern_start = Some(m.start());
+                return Some(FinishReason::StopPattern);
+            }
+        }
+
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Some(FinishReason::Timeout);
+        }
+
+        let newlines = piece.matches('\n').count();
+        self.newlines += newlines;
+        if self.max_newlines.is_some_and(|max| self.newlines > max) {
+            return Some(FinishReason::Newlines);
+        }
+
+        let Some(detection) = &self.loop_detection else {
+            return None;
+        };
+        if newlines > 0 && self.last_line_repeats() >= detection.line_repeats {
+            return Some(FinishReason::Repetition);
+        }
+        if self.repeats_ngram(detection) {
+            return Some(FinishReason::Repetition);
+        }
+        let keep = detection.max_ngram * detection.ngram_repeats;
+        if self.pieces.len() > keep * 2 {
+            self.pieces.drain(..self.pieces.len() - keep);
+        }
+
+        None
+    }
+
+    // How many times in a row the last completed line has occurred.
+    fn last_line_repeats(&self) -> usize {
+        let completed = &self.text[..self.text.rfind('\n').unwrap_or(0)];
+        let mut lines = completed.split('\n').rev().map(str::trim);
+        match lines.next() {
+            Some(last) if !last.is_empty() => 1 + lines.take_while(|line| *line == last).count(),
+            _ => 0,
+        }
+    }
+
+    fn repeats_ngram(&self, detection: &LoopDetection) -> bool {
+        let repeats = detection.ngram_repeats;
+        (1..=detection.max_ngram).any(|n| {
+            if self.pieces.len() < n * repeats {
+                return false;
+            }
+            let tail = &self.pieces[self.pieces.len() - n * repeats..];
+            let gram = &tail[..n];
+            !gram.concat().trim().is_empty() && tail.chunks(n).all(|chunk| chunk == gram)
+        })
+    }
+
+    /// Why generation was stopped, if it was stopped here.
+    pub(crate) fn reason(&self) -> Option<FinishReason> {
+        self.reason
+    }
+
+    /// Bytes to cut from the end of the generated text, so that a matched stop
+    /// pattern is not part of it.
+    pub(crate) fn trim_len(&self) -> usize {
+        match self.pattern_start {
+            Some(start) => self.kept_len.saturating_sub(start),
+            None => 0,
+        }
+    }
+}
+
+/// Finds stop sequences in streamed text. Text that could still turn into a
+/// stop sequence is held back until the next piece decides it.
+pub struct StopMatcher {
+    stops: Vec<String>,
+    pending: String,
+    stopped: bool,
+}
+
+impl StopMatcher {
+    pub fn new(stops: Vec<String>) -> Self {
+        Self {
+            stops: stops.into_iter().filter(|s| !s.is_empty()).collect(),
+            pending: String::new(),
+            stopped: false,
+        }
+    }
+
+    /// Adds the next piece and returns the text that is now known not to be
+    /// part of a stop sequence.
+    pub fn push(&mut self, piece: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        self.pending.push_str(piece);
+
+        if let Some(start) = self
+            .stops
+            .iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()))
+            .min()
+        {
+            self.stopped = true;
+            let safe = self.pending[..start].to_string();
+            self.pending.clear();
+            return safe;
+        }
+
+        // hold back the longest tail that a stop sequence starts with
+        let held = self
+            .stops
+            .iter()
+            .flat_map(|stop| {
+                (1..stop.len().min(self.pending.len() + 1))
+                    .filter(|&n| stop.is_char_boundary(n))
+                    .filter(|&n| self.pending.ends_with(&stop[..n]))
+            })
+            .max()
+            .unwrap_or(0);
+
+        self.pending.drain(..self.pending.len() - held).collect()
+    }
+
+    /// Whether a stop sequence was found.
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Releases the held back text once no more pieces will come.
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// The generated text of one predict as it streams through the token callback.
+pub(crate) struct TokenStream {
+    pub(crate) matcher: StopMatcher,
+    pub(crate) conditions: Option<StopConditions>,
+    pub(crate) output: String,
+    // Bytes of generated text, including any cut from the output later.
+    pub(crate) generated: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conditions(opts: PredictOptions) -> StopConditions {
+        StopConditions::new(&opts).unwrap().unwrap()
+    }
+
+    fn patterns(patterns: &[&str]) -> StopConditions {
+        conditions(PredictOptions {
+            stop_patterns: patterns.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        })
+    }
+
+    // Feeds `pieces` until one stops generation, returns the kept output.
+    fn run(conditions: &mut StopConditions, pieces: &[&str]) -> String {
+        let mut output = String::new();
+        for piece in pieces {
+            if !conditions.check(piece) {
+                break;
+            }
+            output.push_str(piece);
+        }
+        output.truncate(output.len() - conditions.trim_len());
+        output
+    }
+
+    #[test]
+    fn no_conditions() {
+        assert!(StopConditions::new(&PredictOptions::default())
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            StopConditions::new(&PredictOptions {
+                stop_patterns: vec!["(".to_string()],
+                ..Default::default()
+            }),
+            Err(LlamaError::InvalidOptions(_))
+        ));
+    }
+
+    #[test]
+    fn pattern_split_across_pieces() {
+        let mut stop = patterns(&[r"\nQ:"]);
+        let output = run(&mut stop, &["The answer is 4", "2.\nQ", ":", " next"]);
+        assert_eq!(output, "The answer is 42.");
+        assert_eq!(stop.reason(), Some(FinishReason::StopPattern));
+    }
+
+    #[test]
+    fn pattern_anchors_use_the_whole_output() {
+        let mut stop = patterns(&["^Q", r"\bend\b"]);
+        assert_eq!(run(&mut stop, &["A ", "Q", " we", "ekend"]), "A Q weekend");
+        assert_eq!(stop.reason(), None);
+        assert_eq!(run(&mut stop, &[" the ", "end"]), " the ");
+        assert_eq!(stop.reason(), Some(FinishReason::StopPattern));
+    }
+
+    #[test]
+    fn pattern_window_starts_at_a_char_boundary() {
+        let mut stop = patterns(&["ab"]);
+        assert_eq!(run(&mut stop, &["ééé", "a", "b"]), "ééé");
+    }
+
+    #[test]
+    fn unbounded_patterns_match_within_the_window() {
+        let mut stop = patterns(&["x.*y"]);
+        let mut pieces = vec!["x"];
+        pieces.extend([" filler"; 100]);
+        pieces.push("y");
+        assert_eq!(run(&mut stop, &pieces), "");
+
+        let mut stop = patterns(&["x.*y"]);
+        let mut pieces = vec!["x"];
+        pieces.extend([" filler"; STOP_PATTERN_WINDOW]);
+        pieces.push("y");
+        run(&mut stop, &pieces);
+        assert_eq!(stop.reason(), None);
+    }
+
+    #[test]
+    fn newline_limit() {
+        let mut stop = conditions(PredictOptions {
+            max_newlines: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(run(&mut stop, &["a\n", "b\n", "c", "\n", "d"]), "a\nb\nc");
+        assert_eq!(stop.reason(), Some(FinishReason::Newlines));
+    }
+
+    #[test]
+    fn deadline() {
+        let mut stop = conditions(PredictOptions {
+            max_duration: Some(Duration::ZERO),
+            ..Default::default()
+        });
+        assert!(!stop.check("a"));
+        assert_eq!(stop.reason(), Some(FinishReason::Timeout));
+        assert!(!stop.check("b"));
+    }
+
+    #[test]
+    fn repeated_lines() {
+        let mut stop = conditions(PredictOptions {
+            loop_detection: Some(LoopDetection::default()),
+            ..Default::default()
+        });
+        let output = run(&mut stop, &["ok", "\n", "fine\n", "fine\n", "fine\n", "x"]);
+        assert_eq!(output, "ok\nfine\nfine\n");
+        assert_eq!(stop.reason(), Some(FinishReason::Repetition));
+    }
+
+    #[test]
+    fn repeated_ngrams() {
+        let mut stop = conditions(PredictOptions {
+            loop_detection: Some(LoopDetection {
+                max_ngram: 2,
+                ngram_repeats: 3,
+                line_repeats: 3,
+            }),
+            ..Default::default()
+        });
+        let output = run(&mut stop, &["la", " da", "la", " da", "la", " da", "!"]);
+        assert_eq!(output, "la dala dala");
+        assert_eq!(stop.reason(), Some(FinishReason::Repetition));
+
+        // whitespace repeating isn't a loop
+        let mut stop = conditions(PredictOptions {
+            loop_detection: Some(LoopDetection::default()),
+            ..Default::default()
+        });
+        run(&mut stop, &[" "; 20]);
+        assert_eq!(stop.reason(), None);
+    }
+
+    fn push_all(matcher: &mut StopMatcher, pieces: &[&str]) -> Vec<String> {
+        pieces.iter().map(|piece| matcher.push(piece)).collect()
+    }
+
+    #[test]
+    fn matcher_holds_back_possible_stops() {
+        let mut matcher = StopMatcher::new(vec!["###".to_string()]);
+        assert_eq!(
+            push_all(&mut matcher, &["Hello #", "#", "x", " ##"]),
+            ["Hello ", "", "##x", " "]
+        );
+        assert!(!matcher.stopped());
+        assert_eq!(matcher.flush(), "##");
+    }
+
+    #[test]
+    fn matcher_finds_stops_split_across_pieces() {
+        let mut matcher = StopMatcher::new(vec!["User:".to_string(), "###".to_string()]);
+        assert_eq!(
+            push_all(&mut matcher, &["Hi", " Us", "er", ": more", "after"]),
+            ["Hi", " ", "", "", ""]
+        );
+        assert!(matcher.stopped());
+        assert_eq!(matcher.flush(), "");
+    }
+
+    #[test]
+    fn matcher_stops_at_the_earliest_stop() {
+        let mut matcher = StopMatcher::new(vec!["b".to_string(), "a".to_string()]);
+        assert_eq!(matcher.push("xab"), "x");
+    }
+
+    #[test]
+    fn matcher_handles_multibyte_stops() {
+        let mut matcher = StopMatcher::new(vec!["»end".to_string()]);
+        assert_eq!(push_all(&mut matcher, &["x»", "en", "d!"]), ["x", "", ""]);
+        assert!(matcher.stopped());
+
+        // "ê" shares its first byte with "é" but never starts it
+        let mut matcher = StopMatcher::new(vec!["é".to_string()]);
+        assert_eq!(push_all(&mut matcher, &["ê", "aé"]), ["ê", "a"]);
+    }
+
+    #[test]
+    fn matcher_ignores_empty_stops() {
+        let mut matcher = StopMatcher::new(vec![String::new()]);
+        assert_eq!(matcher.push("abc"), "abc");
+        assert!(!matcher.stopped());
+    }
+}
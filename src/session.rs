@@ -1,14 +1,18 @@
 use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 
-use crate::USER_CHATTING_NAME;
+use crate::{
+    llama::options::{LoraAdapter, PredictOptions},
+    USER_CHATTING_NAME,
+};
 
 lazy_static! {
     pub(crate) static ref CURRENT_SESSION: Mutex<Session> = Mutex::new(Session::new());
 }
 
-#[derive(Default)]
-struct IOPair {
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub(crate) struct IOPair {
     input: String,
     output: String,
 }
@@ -16,6 +20,12 @@ struct IOPair {
 #[derive(Default)]
 pub(crate) struct Session {
     pairs: Vec<IOPair>,
+    // The adapters this session predicts with, `None` for whatever is attached.
+    lora_adapters: Option<Vec<LoraAdapter>>,
+    // Alias of the registry model this session uses, `None` for the default one.
+    model: Option<String>,
+    // Options restored from a checkpoint, `None` for the chat defaults.
+    options: Option<PredictOptions>,
 }
 
 impl Session {
@@ -67,4 +77,40 @@ impl Session {
     pub(crate) fn clear(&mut self) {
         self.pairs.clear();
     }
+
+    pub(crate) fn pairs(&self) -> &[IOPair] {
+        &self.pairs
+    }
+
+    pub(crate) fn replace_pairs(&mut self, pairs: Vec<IOPair>) {
+        self.pairs = pairs;
+    }
+
+    pub(crate) fn lora_adapters(&self) -> Option<&Vec<LoraAdapter>> {
+        self.lora_adapters.as_ref()
+    }
+
+    pub(crate) fn set_lora_adapters(&mut self, adapters: Vec<LoraAdapter>) {
+        self.lora_adapters = Some(adapters);
+    }
+
+    pub(crate) fn clear_lora_adapters(&mut self) {
+        self.lora_adapters = None;
+    }
+
+    pub(crate) fn options(&self) -> Option<&PredictOptions> {
+        self.options.as_ref()
+    }
+
+    pub(crate) fn set_options(&mut self, options: PredictOptions) {
+        self.options = Some(options);
+    }
+
+    pub(crate) fn model(&self) -> Option<&str> {
+        self.model.as_deref()
+    }
+
+    pub(crate) fn set_model(&mut self, model: Option<String>) {
+        self.model = model;
+    }
 }
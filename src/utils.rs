@@ -15,3 +15,41 @@ pub fn current_time_mills() -> i64 {
 pub async fn sleep(ms: u32) {
     tokio::time::sleep(Duration::from_millis(ms as u64)).await;
 }
+
+const PROGRESS_BAR_WIDTH: usize = 30;
+
+/// `[=========>          ]  45%` for a fraction between 0 and 1.
+pub fn progress_bar(fraction: f32) -> String {
+    let fraction = fraction.clamp(0.0, 1.0);
+    let filled = (fraction * PROGRESS_BAR_WIDTH as f32) as usize;
+    let head = if filled < PROGRESS_BAR_WIDTH { ">" } else { "" };
+    format!(
+        "[{}{}{}] {:>3.0}%",
+        "=".repeat(filled),
+        head,
+        " ".repeat(PROGRESS_BAR_WIDTH - filled - head.len()),
+        fraction * 100.0
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_bar_fills_with_the_fraction() {
+        assert_eq!(progress_bar(0.0), format!("[>{}]   0%", " ".repeat(29)));
+        assert_eq!(
+            progress_bar(0.5),
+            format!("[{}>{}]  50%", "=".repeat(15), " ".repeat(14))
+        );
+        assert_eq!(progress_bar(1.0), format!("[{}] 100%", "=".repeat(30)));
+    }
+
+    #[test]
+    fn progress_bar_clamps_the_fraction() {
+        assert_eq!(progress_bar(-0.5), progress_bar(0.0));
+        assert_eq!(progress_bar(1.5), progress_bar(1.0));
+        assert_eq!(progress_bar(f32::NAN).len(), progress_bar(0.0).len());
+    }
+}
@@ -0,0 +1,198 @@
+//! Tests that need a real model, ignored by default. Run them with
+//! `ECHOMA_TEST_MODEL=/path/to/model.gguf cargo test --test model -- --ignored`.
+
+use std::sync::{Arc, Mutex};
+
+use echoma::llama::{
+    manager::ModelSlot,
+    options::{ModelOptions, PredictOptions},
+    LLama,
+};
+
+fn model() -> LLama {
+    let path = std::env::var("ECHOMA_TEST_MODEL").expect("set ECHOMA_TEST_MODEL to a GGUF model");
+    LLama::new(path, &ModelOptions::default()).unwrap()
+}
+
+#[test]
+#[ignore = "needs a model, set ECHOMA_TEST_MODEL"]
+fn predict_n_repeats_with_the_same_seed() {
+    let llama = model();
+    let streamed = Arc::new(Mutex::new(String::new()));
+    let run = |seed| {
+        let sink = streamed.clone();
+        let opts = PredictOptions {
+            n: 3,
+            seed,
+            tokens: 24,
+            temperature: 0.9,
+            token_callback: Some(Box::new(move |token| {
+                sink.lock().unwrap().push_str(&token);
+                true
+            })),
+            ..Default::default()
+        };
+        llama
+            .predict_n("Once upon a time".to_string(), opts)
+            .unwrap()
+            .into_iter()
+            .map(|res| res.text)
+            .collect::<Vec<_>>()
+    };
+
+    let first = run(42);
+    assert_eq!(first.len(), 3);
+    // every completion streams through the callback
+    let text = std::mem::take(&mut *streamed.lock().unwrap());
+    assert!(first
+        .iter()
+        .all(|completion| text.contains(completion.as_str())));
+
+    assert_eq!(first, run(42));
+    assert_ne!(first, run(43));
+}
+
+#[test]
+#[ignore = "needs a model and a draft model, set ECHOMA_TEST_MODEL and ECHOMA_TEST_DRAFT_MODEL"]
+fn speculative_decoding_matches_greedy() {
+    let path = std::env::var("ECHOMA_TEST_MODEL").expect("set ECHOMA_TEST_MODEL to a GGUF model");
+    let draft = std::env::var("ECHOMA_TEST_DRAFT_MODEL")
+        .expect("set ECHOMA_TEST_DRAFT_MODEL to a GGUF model sharing the vocabulary");
+    let llama = LLama::new(
+        path,
+        &ModelOptions {
+            draft_model: Some(draft),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let run = |draft_tokens, ignore_eos| {
+        let opts = PredictOptions {
+            tokens: 64,
+            temperature: 0.0,
+            penalty: 1.1,
+            // the whole context, as llama_predict does
+            repeat: -1,
+            ignore_eos,
+            draft_tokens,
+            ..Default::default()
+        };
+        llama
+            .predict("The capital of France".to_string(), opts)
+            .unwrap()
+    };
+
+    for ignore_eos in [false, true] {
+        let greedy = run(0, ignore_eos);
+        assert!(greedy.speculative.is_none());
+        let speculative = run(8, ignore_eos);
+        assert!(speculative.speculative.is_some());
+        assert_eq!(speculative.text, greedy.text);
+        assert_eq!(speculative.finish_reason, greedy.finish_reason);
+        assert_eq!(speculative.completion_tokens, greedy.completion_tokens);
+    }
+}
+
+#[test]
+#[ignore = "needs a model, set ECHOMA_TEST_MODEL"]
+fn concurrent_predicts_share_a_model() {
+    let llama = Arc::new(model());
+    let opts = || PredictOptions {
+        tokens: 16,
+        temperature: 0.0,
+        ..Default::default()
+    };
+    let expected = llama.predict("Hello".to_string(), opts()).unwrap().text;
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let llama = llama.clone();
+            std::thread::spawn(move || llama.predict("Hello".to_string(), opts()).unwrap().text)
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), expected);
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs a model, set ECHOMA_TEST_MODEL"]
+async fn releasing_a_model_marks_it_used() {
+    let path = std::env::var("ECHOMA_TEST_MODEL").expect("set ECHOMA_TEST_MODEL to a GGUF model");
+    let slot = ModelSlot::new(path, ModelOptions::default());
+    let llama = slot.get().await.unwrap();
+    let taken = slot.last_used();
+
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    drop(llama);
+    assert!(slot.last_used() > taken);
+}
+
+#[test]
+#[ignore = "needs a model, set ECHOMA_TEST_MODEL"]
+fn restored_state_continues_generation() {
+    let llama = model();
+    let opts = || PredictOptions {
+        tokens: 16,
+        temperature: 0.0,
+        ..Default::default()
+    };
+    let prompt = "The quick brown fox".to_string();
+    llama.predict(prompt.clone(), opts()).unwrap();
+    // the context is far from full, so this is only the used part of it
+    let state = llama.state_bytes();
+    let kv_tokens = llama.kv_tokens();
+    let continued = format!("{} jumps over", prompt);
+    let expected = llama.predict(continued.clone(), opts()).unwrap();
+
+    llama
+        .predict("Something else entirely".to_string(), opts())
+        .unwrap();
+    llama.set_state_bytes(&state, &kv_tokens).unwrap();
+    assert_eq!(llama.kv_tokens(), kv_tokens);
+    let restored = llama.predict(continued, opts()).unwrap();
+    assert!(restored.cached_tokens > 0);
+    assert_eq!(restored.text, expected.text);
+}
+
+#[test]
+#[ignore = "needs a model, set ECHOMA_TEST_MODEL"]
+fn failed_predict_leaves_nothing_behind() {
+    let llama = model();
+    let opts = PredictOptions {
+        tokens: 4,
+        logprobs: Some(2),
+        logit_bias: [(i32::MAX, 1.0)].into_iter().collect(),
+        ..Default::default()
+    };
+    assert!(llama.predict("Hello".to_string(), opts).is_err());
+
+    let opts = PredictOptions {
+        tokens: 4,
+        ..Default::default()
+    };
+    let res = llama.predict("Hello".to_string(), opts).unwrap();
+    assert!(res.logprobs.is_none());
+}
+
+#[test]
+#[ignore = "needs a model, set ECHOMA_TEST_MODEL"]
+fn trained_context_size_allows_beam_search() {
+    let path = std::env::var("ECHOMA_TEST_MODEL").expect("set ECHOMA_TEST_MODEL to a GGUF model");
+    // 0 means the context the model was trained on
+    let llama = LLama::new(
+        path,
+        &ModelOptions {
+            context_size: 0,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let opts = PredictOptions {
+        tokens: 8,
+        beam_width: 2,
+        ..Default::default()
+    };
+    let res = llama.predict("Once upon a time".to_string(), opts).unwrap();
+    assert!(res.completion_tokens > 0);
+}